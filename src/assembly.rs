@@ -0,0 +1,435 @@
+use std::{collections::{HashMap, HashSet}, fmt};
+
+/// The instructions of LMC assembly
+const MNEMONICS: [&str; 12] = ["ADD", "SUB", "STA", "LDA", "BRA", "BRZ", "BRP", "INP", "OUT", "OTC", "HLT", "DAT"];
+
+/// Written after an instruction which was written by the programmer, so that the optimiser leaves it as it is.
+/// It is removed by strip_verbatim before the program is output
+pub const VERBATIM: &str = "!verbatim";
+
+/// Whether a string is one of the instructions of LMC assembly
+pub fn is_mnemonic(s: &str) -> bool {
+    MNEMONICS.contains(&s)
+}
+
+/// A line of LMC assembly, split into its parts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Line<'a> {
+    pub label: Option<&'a str>,
+    pub mnemonic: &'a str,
+    pub operand: Option<&'a str>,
+    /// Whether the instruction was written by the programmer, and should be output as it is
+    pub verbatim: bool,
+}
+
+impl fmt::Display for Line<'_> {
+    /// Writes the line as assembly, keeping the verbatim marker
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(label) = self.label {
+            write!(f, "{label} ")?;
+        }
+        write!(f, "{}", self.mnemonic)?;
+        if let Some(operand) = self.operand {
+            write!(f, " {operand}")?;
+        }
+        if self.verbatim {
+            write!(f, " {VERBATIM}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Splits a line of assembly into its label, mnemonic and operand.
+/// Returns None for a line with no instruction.
+/// Only the first label of a line is kept, so programs should go through merge_labels first
+pub fn split_line(line: &str) -> Option<Line<'_>> {
+    let mut parts = line.split_whitespace().peekable();
+    // Everything before the instruction is a label
+    let label = parts.next_if(|p| !MNEMONICS.contains(p));
+    while parts.next_if(|p| !MNEMONICS.contains(p)).is_some() {}
+    let mnemonic = parts.next()?;
+    let operand = parts.next_if(|p| *p != VERBATIM);
+    Some(Line { label, mnemonic, operand, verbatim: parts.next() == Some(VERBATIM) })
+}
+
+/// Removes the markers from instructions written by the programmer, once nothing else will change the program
+pub fn strip_verbatim(program: &str) -> String {
+    program.replace(&format!(" {VERBATIM}"), "")
+}
+
+/// The style of labels used in the output
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Target {
+    /// Descriptive labels like var_a, const_1 and while_3_end
+    #[default]
+    Default,
+    /// Short labels like v0, c0 and l0, for emulators that limit label length or dislike underscores
+    Short,
+}
+
+impl Target {
+    /// Gets the target with the given name, as used on the command line
+    pub fn from_name(name: &str) -> Option<Target> {
+        match name {
+            "default" => Some(Target::Default),
+            "short" => Some(Target::Short),
+            _ => None
+        }
+    }
+
+    /// Gets the label for the target, given the label the compiler generated and a counter of labels of that kind
+    fn label(&self, label: &str, counters: &mut HashMap<char, usize>) -> String {
+        match self {
+            Target::Default => label.to_string(),
+            Target::Short => {
+                // Variables and constants keep a distinct prefix so they can be told apart
+                let prefix = if label.starts_with("var_") || label.starts_with("local_") {
+                    'v'
+                } else if label.starts_with("const_") {
+                    'c'
+                } else {
+                    'l'
+                };
+                let counter = counters.entry(prefix).or_insert(0);
+                *counter += 1;
+                format!("{prefix}{}", *counter - 1)
+            }
+        }
+    }
+}
+
+/// Renames every label in the program to the style of the target.
+/// All labels in the output go through this function.
+pub fn rename_labels(program: &str, target: Target) -> String {
+    if target == Target::Default {
+        return program.to_string();
+    }
+
+    let mut names: HashMap<String, String> = HashMap::new();
+    let mut counters: HashMap<char, usize> = HashMap::new();
+
+    // Operands which aren't labels in the program, like numbers in instructions written by the programmer, are kept
+    let labels: HashSet<&str> = program.lines().filter_map(split_line).filter_map(|l| l.label).collect();
+
+    let mut renamed = String::new();
+    for line in program.lines() {
+        let split = match split_line(line) {
+            // Keep blank lines
+            None => {
+                renamed += "\n";
+                continue
+            }
+            Some(l) => l
+        };
+
+        let mut rename = |label: &str| -> String {
+            names.entry(label.to_string()).or_insert_with(|| target.label(label, &mut counters)).clone()
+        };
+
+        if let Some(label) = split.label {
+            renamed += &rename(label);
+            renamed += " ";
+        }
+        renamed += split.mnemonic;
+        if let Some(operand) = split.operand {
+            // Operands of DAT are values rather than labels
+            if split.mnemonic != "DAT" && labels.contains(operand) {
+                renamed += &format!(" {}", rename(operand));
+            } else {
+                renamed += &format!(" {operand}");
+            }
+        }
+        if split.verbatim {
+            renamed += &format!(" {VERBATIM}");
+        }
+        renamed += "\n";
+    }
+    renamed
+}
+
+/// Finds a label which is defined on more than one line, which would make the program ambiguous
+pub fn find_duplicate_label(program: &str) -> Option<&str> {
+    let mut seen = HashSet::new();
+    program.lines()
+        .filter_map(split_line)
+        .filter_map(|l| l.label)
+        .find(|label| !seen.insert(*label))
+}
+
+/// Makes sure each instruction has at most one label.
+/// Labels for the same instruction, including labels on lines with no instruction, are merged into the first one,
+/// and references to the others are replaced.
+pub fn merge_labels(program: &str) -> String {
+    // The instructions, with their label
+    let mut lines: Vec<(Option<&str>, Vec<&str>)> = Vec::new();
+    // Labels which will be merged into other labels
+    let mut aliases: HashMap<&str, &str> = HashMap::new();
+    // Labels waiting for an instruction
+    let mut pending: Vec<&str> = Vec::new();
+
+    for line in program.lines() {
+        let mut parts = line.split_whitespace().peekable();
+        while let Some(label) = parts.next_if(|p| !MNEMONICS.contains(p)) {
+            pending.push(label);
+        }
+        let instruction: Vec<&str> = parts.collect();
+        if instruction.is_empty() {
+            continue;
+        }
+
+        let label = pending.first().copied();
+        for alias in pending.drain(..).skip(1) {
+            aliases.insert(alias, label.unwrap_or_default());
+        }
+        lines.push((label, instruction));
+    }
+
+    let mut merged = String::new();
+    for (label, instruction) in lines {
+        if let Some(label) = label {
+            merged += &format!("{label} ");
+        }
+        merged += instruction[0];
+        for operand in &instruction[1..] {
+            merged += &format!(" {}", aliases.get(operand).unwrap_or(operand));
+        }
+        merged += "\n";
+    }
+    merged
+}
+
+/// Whether an instruction only changes the accumulator, so it can be removed if the accumulator is overwritten before being used
+fn only_sets_accumulator(line: &Line) -> bool {
+    matches!(line.mnemonic, "LDA" | "ADD" | "SUB")
+}
+
+/// Finds a store which is overwritten before it is read, returning its index.
+/// Stops looking at labels and branches, as other code could read the value.
+/// Works backwards in one pass, so long runs of stores don't take quadratic time.
+fn find_dead_store(lines: &[Line]) -> Option<usize> {
+    // Operands which are stored to later in the same run of instructions, before anything reads them
+    let mut overwritten: HashSet<&str> = HashSet::new();
+    for (i, line) in lines.iter().enumerate().rev() {
+        if line.label.is_some() || matches!(line.mnemonic, "BRA" | "BRZ" | "BRP" | "HLT") {
+            overwritten.clear();
+            continue;
+        }
+        match (line.mnemonic, line.operand) {
+            ("STA", Some(operand)) => {
+                if !line.verbatim && overwritten.contains(operand) {
+                    return Some(i);
+                }
+                overwritten.insert(operand);
+            },
+            (_, Some(operand)) => {
+                overwritten.remove(operand);
+            },
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Finds an instruction whose result is overwritten by the next instruction, returning its index
+fn find_dead_load(lines: &[Line]) -> Option<usize> {
+    lines.windows(2).position(|pair| {
+        only_sets_accumulator(&pair[0]) && !pair[0].verbatim && pair[1].label.is_none() && matches!(pair[1].mnemonic, "LDA" | "INP")
+    })
+}
+
+/// Finds a load of the value that was just stored, returning its index.
+/// The accumulator still holds the value, unless something branches to the load
+fn find_reload(lines: &[Line]) -> Option<usize> {
+    lines.windows(2).position(|pair| {
+        pair[0].mnemonic == "STA" && pair[1].mnemonic == "LDA" && pair[1].label.is_none() && !pair[1].verbatim && pair[0].operand == pair[1].operand
+    }).map(|i| i + 1)
+}
+
+/// Finds a branch to the instruction straight after it, returning its index.
+/// Conditions emit these for the true case, which loops run every iteration, and for empty bodies.
+fn find_branch_to_next(lines: &[Line]) -> Option<usize> {
+    lines.windows(2).position(|pair| {
+        matches!(pair[0].mnemonic, "BRA" | "BRZ" | "BRP") && pair[0].label.is_none() && !pair[0].verbatim && pair[0].operand.is_some() && pair[0].operand == pair[1].label
+    })
+}
+
+/// Removes instructions that have no effect:
+/// stores to variables that are overwritten before they are read, the code that calculated the stored values,
+/// loads of values which are already in the accumulator, and branches to the next instruction.
+/// Instructions written by the programmer are never removed.
+/// The program should only contain instructions, not data.
+pub fn optimise(program: &str) -> String {
+    // Each instruction needs at most one label, so that the passes can see every label
+    let merged = merge_labels(program);
+    let mut lines: Vec<Line> = merged.lines().filter_map(split_line).collect();
+
+    loop {
+        if let Some(i) = find_dead_store(&lines) {
+            lines.remove(i);
+        }
+        else if let Some(i) = find_dead_load(&lines) {
+            // Keep any label on the removed instruction
+            let removed = lines.remove(i);
+            lines[i].label = removed.label;
+        }
+        else if let Some(i) = find_reload(&lines) {
+            lines.remove(i);
+        }
+        else if let Some(i) = find_branch_to_next(&lines) {
+            lines.remove(i);
+        }
+        else {
+            break;
+        }
+    }
+
+    let mut result = String::new();
+    for line in lines {
+        result += &format!("{line}\n");
+    }
+    result
+}
+
+/// Whether an instruction is the last of a basic block, as it branches or stops the program
+fn ends_block(line: &Line) -> bool {
+    matches!(line.mnemonic, "BRA" | "BRZ" | "BRP" | "HLT")
+}
+
+/// Describes the control flow of a program as a Graphviz dot graph.
+/// Each node is a basic block, a run of instructions which can only be branched to at the start and only branches at the end.
+/// Branches are labelled with their instruction, and falling through to the next block isn't labelled
+pub fn control_flow_graph(program: &str) -> String {
+    let lines = program.lines().filter_map(split_line).take_while(|l| l.mnemonic != "DAT");
+
+    // Blocks start at labels and after branches
+    let mut blocks: Vec<Vec<Line>> = Vec::new();
+    for line in lines {
+        match blocks.last_mut() {
+            Some(block) if line.label.is_none() && !block.last().is_some_and(ends_block) => block.push(line),
+            _ => blocks.push(vec![line])
+        }
+    }
+
+    // Blocks are named by their label, if they have one
+    let names: Vec<String> = blocks.iter().enumerate()
+        .map(|(i, block)| block[0].label.map(str::to_string).unwrap_or_else(|| format!("block_{i}")))
+        .collect();
+
+    let mut graph = String::from("digraph program {\n    node [shape=box];\n");
+    for (i, block) in blocks.iter().enumerate() {
+        let instructions: Vec<String> = block.iter()
+            .map(|l| format!("{}{}", l.mnemonic, l.operand.map(|o| format!(" {o}")).unwrap_or_default()))
+            .collect();
+        graph += &format!("    \"{}\" [label=\"{}\"];\n", names[i], instructions.join("\\n"));
+
+        let last = block[block.len() - 1];
+        if let ("BRA" | "BRZ" | "BRP", Some(target)) = (last.mnemonic, last.operand) {
+            graph += &format!("    \"{}\" -> \"{target}\" [label=\"{}\"];\n", names[i], last.mnemonic);
+        }
+        if !matches!(last.mnemonic, "BRA" | "HLT") && i + 1 < blocks.len() {
+            graph += &format!("    \"{}\" -> \"{}\";\n", names[i], names[i + 1]);
+        }
+    }
+    graph += "}\n";
+    graph
+}
+
+/// The layout of the assembly, for different emulators
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Dialect {
+    /// One instruction per line, with labels before them
+    #[default]
+    Default,
+    /// For Peter Higginson's online LMC simulator: labels in the first column, and instructions in the second
+    Phi,
+}
+
+impl Dialect {
+    /// Gets the dialect with the given name, as used on the command line
+    pub fn from_name(name: &str) -> Option<Dialect> {
+        match name {
+            "default" => Some(Dialect::Default),
+            "phi" => Some(Dialect::Phi),
+            _ => None
+        }
+    }
+}
+
+/// Lays out the program for the dialect
+pub fn format_dialect(program: &str, dialect: Dialect) -> String {
+    if dialect == Dialect::Default {
+        return program.to_string();
+    }
+
+    let mut formatted = String::new();
+    for line in program.lines().filter_map(split_line) {
+        formatted += &format!("{}\t{}", line.label.unwrap_or_default(), line.mnemonic.to_uppercase());
+        if let Some(operand) = line.operand {
+            formatted += &format!("\t{operand}");
+        }
+        formatted += "\n";
+    }
+    formatted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn optimise_removes_overwritten_store() {
+        assert_eq!(optimise("LDA a\nSTA x\nLDA b\nSTA x\nHLT\n"), "LDA b\nSTA x\nHLT\n");
+    }
+
+    #[test]
+    fn optimise_keeps_store_before_label() {
+        let program = "LDA a\nSTA x\nloop LDA b\nSTA x\nBRA loop\n";
+        assert_eq!(optimise(program), program);
+    }
+
+    #[test]
+    fn optimise_keeps_operands_with_several_labels() {
+        assert_eq!(optimise("LDA a\nBRZ first\nfirst second ADD b\nOUT\nBRA second\n"), "LDA a\nfirst ADD b\nOUT\nBRA first\n");
+    }
+
+    #[test]
+    fn split_line_skips_extra_labels() {
+        let line = split_line("first second ADD b").expect("Line should have an instruction");
+        assert_eq!(line.label, Some("first"));
+        assert_eq!(line.mnemonic, "ADD");
+        assert_eq!(line.operand, Some("b"));
+    }
+
+    #[test]
+    fn optimise_removes_branch_to_next() {
+        assert_eq!(optimise("BRZ a\nBRA b\nb OUT\na HLT\n"), "BRZ a\nb OUT\na HLT\n");
+    }
+
+    #[test]
+    fn merge_labels_replaces_aliases() {
+        assert_eq!(merge_labels("first\nsecond LDA x\nBRA second\n"), "first LDA x\nBRA first\n");
+    }
+
+    #[test]
+    fn optimise_removes_reload() {
+        assert_eq!(optimise("INP\nSTA x\nLDA x\nOUT\nHLT\n"), "INP\nSTA x\nOUT\nHLT\n");
+    }
+
+    #[test]
+    fn optimise_keeps_reload_after_label() {
+        let program = "INP\nSTA x\nback LDA x\nOUT\nBRA back\n";
+        assert_eq!(optimise(program), program);
+    }
+
+    #[test]
+    fn optimise_keeps_verbatim_instructions() {
+        let program = format!("STA x {VERBATIM}\nLDA x {VERBATIM}\nLDA y\nBRA next {VERBATIM}\nnext HLT\n");
+        assert_eq!(optimise(&program), program);
+    }
+
+    #[test]
+    fn find_duplicate_label_finds_repeated_label() {
+        assert_eq!(find_duplicate_label("a LDA x\nb OUT\na HLT\n"), Some("a"));
+        assert_eq!(find_duplicate_label("a LDA x\nb OUT\nHLT\n"), None);
+    }
+}
@@ -1,4 +1,6 @@
-use std::{collections::{HashMap, HashSet}};
+use std::{collections::{BTreeMap, BTreeSet, HashSet}, fmt};
+
+use crate::assembly::{self, Dialect, Target};
 
 /// Types of token output by the tokeniser
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -6,16 +8,35 @@ enum TokenType<'a> {
     /// Any token not matched by another token
     Identifier(&'a str),
     Number(i32),
+    /// Text in double quotes, without the quotes
+    Str(&'a str),
     NewLine,
     If,
     EndIf,
     Else,
+    Elif,
     While,
     EndWhile,
+    Repeat,
+    EndRepeat,
+    Loop,
+    EndLoop,
+    Case,
+    When,
+    EndCase,
     Break,
+    Exit,
     Input,
     Output,
+    Var,
+    In,
+    Not,
+    Asm,
+    Rotate,
+    Dump,
+    Readonly,
     True,
+    False,
     OperatorAdd,
     OperatorSub,
     OperatorAssignment,
@@ -27,71 +48,338 @@ enum TokenType<'a> {
     OperatorLessThanInclusive,
 }
 
+impl fmt::Display for TokenType<'_> {
+    /// Writes the token as it would appear in the source
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Identifier(s) => write!(f, "{s}"),
+            Number(n) => write!(f, "{n}"),
+            Str(s) => write!(f, "\"{s}\""),
+            NewLine => write!(f, "end of line"),
+            If => write!(f, "if"),
+            EndIf => write!(f, "endif"),
+            Else => write!(f, "else"),
+            Elif => write!(f, "elif"),
+            While => write!(f, "while"),
+            EndWhile => write!(f, "endwhile"),
+            Repeat => write!(f, "repeat"),
+            EndRepeat => write!(f, "endrepeat"),
+            Loop => write!(f, "loop"),
+            EndLoop => write!(f, "endloop"),
+            Case => write!(f, "case"),
+            When => write!(f, "when"),
+            EndCase => write!(f, "endcase"),
+            Break => write!(f, "break"),
+            Exit => write!(f, "exit"),
+            Input => write!(f, "input"),
+            Output => write!(f, "output"),
+            Var => write!(f, "var"),
+            In => write!(f, "in"),
+            Not => write!(f, "not"),
+            Asm => write!(f, "asm"),
+            Rotate => write!(f, "rotate"),
+            Dump => write!(f, "dump"),
+            Readonly => write!(f, "readonly"),
+            True => write!(f, "true"),
+            False => write!(f, "false"),
+            OperatorAdd => write!(f, "+"),
+            OperatorSub => write!(f, "-"),
+            OperatorAssignment => write!(f, "="),
+            OperatorInequality => write!(f, "!="),
+            OperatorEquality => write!(f, "=="),
+            OperatorGreaterThan => write!(f, ">"),
+            OperatorLessThan => write!(f, "<"),
+            OperatorGreaterThanInclusive => write!(f, ">="),
+            OperatorLessThanInclusive => write!(f, "<="),
+        }
+    }
+}
+
 /// A token output by the tokeniser
 #[derive(Debug, Clone, PartialEq, Eq)]
 struct Token<'a> {
+    /// The line in the source that the token is on, starting from 1.
+    /// Blank and comment lines are counted so that this matches the line numbers in an editor
     line: usize,
     token_type: TokenType<'a>
 }
 
 use TokenType::*;
 
-/// Takes a string and returns Vec<Token>.
-/// Does not error - any syntax errors will be caught in the parser.
+/// Options controlling how a program is compiled
+#[derive(Debug, Clone)]
+pub struct CompileOptions {
+    /// Makes problems which would otherwise be warnings into errors
+    pub strict: bool,
+    /// The style of labels to emit
+    pub target: Target,
+    /// The layout of the assembly
+    pub dialect: Dialect,
+    /// Warns about variables which are never read
+    pub warn_unused: bool,
+    /// Warns about code which works but is probably a mistake:
+    /// values which are overwritten before they are read, and 'var's which hide another variable
+    pub warn_style: bool,
+    /// Strings which start a comment
+    pub comment_markers: Vec<String>,
+    /// The number of mailboxes the program and its data can use
+    pub max_cells: usize,
+    /// Makes compiling fail if there are any warnings
+    pub warnings_as_errors: bool,
+    /// A character to output after each value that is output, so that they don't run together
+    pub separator: Option<char>,
+    /// Readonly variables defined before the program, with their values.
+    /// These replace any 'readonly' declaration of the same variable in the program
+    pub defines: Vec<(String, i32)>,
+}
+
+impl Default for CompileOptions {
+    fn default() -> Self {
+        CompileOptions {
+            strict: false,
+            target: Target::default(),
+            dialect: Dialect::default(),
+            warn_unused: false,
+            warn_style: false,
+            comment_markers: vec!["//".to_string(), "#".to_string()],
+            max_cells: 100,
+            warnings_as_errors: false,
+            separator: None,
+            defines: Vec::new(),
+        }
+    }
+}
+
+/// A problem with a program which doesn't stop it from compiling
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Warning {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Warning on line {}: {}", self.line, self.message)
+    }
+}
+
+/// The result of compiling a program
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Compiled {
+    /// The LMC assembly
+    pub assembly: String,
+    /// Every constant the program uses, with the lines that use it
+    pub constants: BTreeMap<i32, BTreeSet<usize>>,
+    /// Every variable the program uses, with the lines it is assigned on and the lines it is read on
+    pub variables: BTreeMap<String, (BTreeSet<usize>, BTreeSet<usize>)>,
+    /// Variables made by the compiler, such as loop counters
+    pub hidden_vars: Vec<String>,
+}
+
+/// Writes a set of line numbers like "lines 1, 3", "line 1" or "no lines"
+fn line_list(lines: &BTreeSet<usize>) -> String {
+    let word = if lines.len() == 1 { "line" } else { "lines" };
+    if lines.is_empty() {
+        return format!("no {word}");
+    }
+    let lines: Vec<String> = lines.iter().map(usize::to_string).collect();
+    format!("{word} {}", lines.join(", "))
+}
+
+impl Compiled {
+    /// Lists the constants and the lines they are used on, one per line
+    pub fn dump_constants(&self) -> String {
+        let mut dump = String::new();
+        for (n, lines) in &self.constants {
+            dump += &format!("{} used on {}\n", const_label(*n), line_list(lines));
+        }
+        dump
+    }
+
+    /// Lists every identifier in the program, one per line:
+    /// the variables with the lines they are assigned and read on, then the constants, then the compiler's own variables
+    pub fn list_identifiers(&self) -> String {
+        let mut list = String::new();
+        for (name, (assigned, read)) in &self.variables {
+            list += &format!("{name} assigned on {} and read on {}\n", line_list(assigned), line_list(read));
+        }
+        list += &self.dump_constants();
+        for name in &self.hidden_vars {
+            list += &format!("{name} made by the compiler\n");
+        }
+        list
+    }
+}
+
+/// Whether a token is one of the operators that can be used in a condition
+fn is_comparison_operator(token_type: &TokenType) -> bool {
+    matches!(token_type, OperatorEquality | OperatorInequality | OperatorGreaterThan | OperatorLessThan | OperatorGreaterThanInclusive | OperatorLessThanInclusive)
+}
+
+/// Gets the type of token for a string with no whitespace.
 /// Any string that does not match another token will become an identifier, which means that any string can become an identifier.
-fn tokenise(src: &str) -> Vec<Token> {
-    // Final list of tokens
-    let mut tokens: Vec<Token> = Vec::new();
+fn token_type(token_str: &str) -> TokenType<'_> {
+    // If the token is a number, it is a Number token
+    if let Ok(n) = str::parse::<i32>(token_str) {
+        return Number(n)
+    }
+    // Match specific keywords
+    match token_str {
+        "if" => If,
+        "endif" => EndIf,
+        "else" => Else,
+        "elif" => Elif,
+        "while" => While,
+        "endwhile" => EndWhile,
+        "repeat" => Repeat,
+        "endrepeat" => EndRepeat,
+        "loop" => Loop,
+        "endloop" => EndLoop,
+        "case" => Case,
+        "when" => When,
+        "endcase" => EndCase,
+        "break" => Break,
+        "exit" => Exit,
+        "input" => Input,
+        "output" | "print" => Output,
+        "var" => Var,
+        "in" => In,
+        "not" => Not,
+        "asm" => Asm,
+        "rotate" => Rotate,
+        "dump" => Dump,
+        "readonly" => Readonly,
+        "true" => True,
+        "false" => False,
+        "+" => OperatorAdd,
+        "-" => OperatorSub,
+        "=" => OperatorAssignment,
+        "==" => OperatorEquality,
+        "!=" => OperatorInequality,
+        ">" => OperatorGreaterThan,
+        "<" => OperatorLessThan,
+        ">=" => OperatorGreaterThanInclusive,
+        "<=" => OperatorLessThanInclusive,
+        // Anything else is an identifier
+        s => Identifier(s)
+    }
+}
+
+/// Splits a '-' from the start of a string with no whitespace, so that '-x' is the same as '- x'.
+/// Negative numbers are left as they are.
+fn split_minus(token_str: &str) -> Vec<&str> {
+    match token_str.strip_prefix('-') {
+        Some(rest) if !rest.is_empty() && str::parse::<i32>(token_str).is_err() => vec!["-", rest],
+        _ => vec![token_str]
+    }
+}
+
+/// Splits a line into its code and the comment after it, which starts at the first comment marker.
+/// Comment markers in quotes are part of the string
+fn split_comment<'a>(line: &'a str, comment_markers: &[String]) -> (&'a str, Option<&'a str>) {
+    let outside_quotes = |i: &usize| line[..*i].matches('"').count().is_multiple_of(2);
+    let comment_start = comment_markers.iter()
+        .filter_map(|marker| line.match_indices(marker.as_str()).map(|(i, _)| i).find(outside_quotes))
+        .min();
+    match comment_start {
+        Some(comment_start) => (&line[..comment_start], Some(&line[comment_start..])),
+        None => (line, None)
+    }
+}
+
+/// Takes a string and returns an iterator over its tokens, which are generated as they are needed.
+/// Anything after one of the comment markers on a line is ignored.
+/// Does not error - any syntax errors will be caught in the parser.
+fn tokens<'a>(src: &'a str, comment_markers: &'a [String]) -> impl Iterator<Item = Token<'a>> {
     // Loop over lines of string
+    src.lines().enumerate().flat_map(move |(i, line)| {
+        let line_no = i + 1;
+
+        // Remove any carriage return left from Windows line endings, so it can't be part of a comment marker or token
+        let line = line.trim_end_matches('\r');
+
+        // Ignore anything after the first comment marker
+        let (code, _) = split_comment(line, comment_markers);
+
+        // Separate tokens by whitespace, except in quotes where the text is one token.
+        // Splitting on quotes gives text outside of quotes at even indexes and inside them at odd indexes
+        code.split('"').enumerate()
+            .flat_map(move |(i, part)| {
+                if i % 2 == 1 {
+                    vec![Token { line: line_no, token_type: Str(part) }]
+                }
+                else {
+                    part.split_whitespace()
+                        .flat_map(split_minus)
+                        .map(|token_str| Token { line: line_no, token_type: token_type(token_str) })
+                        .collect()
+                }
+            })
+            // Add newline after every line
+            .chain(std::iter::once(Token { line: line_no, token_type: NewLine }))
+    })
+}
+
+/// Takes a string and returns Vec<Token>.
+/// Does not error - any syntax errors will be caught in the parser.
+fn tokenise<'a>(src: &'a str, comment_markers: &'a [String]) -> Vec<Token<'a>> {
+    tokens(src, comment_markers).collect()
+}
+
+/// Gets the label of the constant with the value n.
+/// Negative constants are called const_neg{n} as '-' can't be used in labels.
+fn const_label(n: i32) -> String {
+    if n < 0 {
+        format!("const_neg{}", n.unsigned_abs())
+    }
+    else {
+        format!("const_{n}")
+    }
+}
+
+/// Negates the number at token index of line_no, erroring if the result doesn't fit in an i32
+fn negate(n: i32, line_no: usize, index: usize) -> Result<i32, String> {
+    n.checked_neg().ok_or(format!("Error on line {line_no} token {index}: The number {n} can't be negated"))
+}
+
+/// Errors if the line has any tokens from line[end] onwards, for statements which take nothing more
+fn expect_line_end(line: &[Token], end: usize) -> Result<(), String> {
+    match line.get(end) {
+        Some(t) => Err(format!("Error on line {} token {end}: Unexpected '{}' after '{}'", t.line, t.token_type, line[end - 1].token_type)),
+        None => Ok(())
+    }
+}
+
+/// Checks that every string is closed on the line it starts on.
+/// The tokeniser doesn't error, so it treats the rest of a line after an unclosed quote as the string
+fn check_quotes(src: &str, comment_markers: &[String]) -> Result<(), String> {
     for (i, line) in src.lines().enumerate() {
-        // Ignore anything after a comment
-        let split_by_comment: Vec<&str> = line.splitn(2, "//").collect();
+        let (code, _) = split_comment(line.trim_end_matches('\r'), comment_markers);
+        if !code.matches('"').count().is_multiple_of(2) {
+            return Err(format!("Error on line {}: Expected a '\"' to close the string", i + 1))
+        }
+    }
+    Ok(())
+}
 
-        // Separate tokens by whitespace
-        for token_str in split_by_comment[0].split_whitespace() {
-            // If the token is a number, add a Number token
-            if let Ok(n) = str::parse::<i32>(token_str) {
-                // Check bounds of LMC ints
-                if n > 999 || n < -999 {
-                    println!("Warning: number {} on line {} is outside the bounds of LMC numbers", n, i);
+/// Checks that all numbers are within the bounds of LMC numbers.
+/// Numbers outside the bounds are a warning, or an error in strict mode.
+fn check_number_bounds(tokens: &[Token], options: &CompileOptions, warnings: &mut Vec<Warning>) -> Result<(), String> {
+    for token in tokens {
+        if let Number(n) = token.token_type {
+            if !(-999..=999).contains(&n) {
+                let line = token.line;
+                if options.strict {
+                    return Err(format!("Error on line {line}: Number {n} is outside the bounds of LMC numbers"));
                 }
-                tokens.push(Token { line: i, token_type: Number(n) })
-            }
-            else {
-                // Match specific keywords
-                let token = match token_str {
-                    "if" => If,
-                    "endif" => EndIf,
-                    "else" => Else,
-                    "while" => While,
-                    "endwhile" => EndWhile,
-                    "break" => Break,
-                    "input" => Input,
-                    "output" | "print" => Output,
-                    "true" => True,
-                    "+" => OperatorAdd,
-                    "-" => OperatorSub,
-                    "=" => OperatorAssignment,
-                    "==" => OperatorEquality,
-                    "!=" => OperatorInequality,
-                    ">" => OperatorGreaterThan,
-                    "<" => OperatorLessThan,
-                    ">=" => OperatorGreaterThanInclusive,
-                    "<=" => OperatorLessThanInclusive,
-                    // Anything else is an identifier
-                    s => Identifier(s)
-                };
-                tokens.push(Token { line: i, token_type: token })
+                warnings.push(Warning { line, message: format!("Number {n} is outside the bounds of LMC numbers") });
             }
         }
-        // Add newline after every line
-        tokens.push(Token { line: i, token_type: NewLine });
     }
-    // Return tokens
-    tokens
+    Ok(())
 }
 
-/// A scope for an if statement or while loop
+/// A scope for an if statement, while loop or repeat loop
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum Scope {
     /// While loop
@@ -99,466 +387,1978 @@ enum Scope {
         /// Used so that the 'endwhile' can emit the correct label
         start_line: usize
     },
+    /// Repeat loop
+    Repeat {
+        /// Used so that the 'endrepeat' can emit the correct label.
+        /// The hidden counter for the loop is called repeat_{line}_count
+        start_line: usize
+    },
+    /// Infinite loop, which is left with 'break'
+    Loop {
+        /// Used so that the 'endloop' can emit the correct label
+        start_line: usize
+    },
+    /// Case statement, which runs the first 'when' matching the value
+    Case {
+        /// Used so that the 'endcase' can emit the correct label.
+        /// The value being matched is kept in case_{line}
+        start_line: usize,
+        /// The line of the last 'when', which jumps to when_{line}_next if it doesn't match
+        when_line: Option<usize>,
+        /// Whether there is an 'else' for when nothing matches
+        has_else: bool
+    },
     If {
         /// The line of the 'if' statement
         if_start_line: usize,
-        /// The line of the 'if' or 'else if' statement
+        /// The line of the 'if', 'else if' or 'else' statement
         else_start_line: usize,
         /// Whether there is an 'else' to the if.
-        /// Controls whether the label emitted by the 'endif' is if_{line}_else or if_{line}_end
-        /// 'else if's don't count for this as they emit the correct symbol anyway
+        /// Controls whether the 'endif' needs to emit if_{else_start_line}_else for the last condition being false.
+        /// 'else if's don't count for this as their condition can still be false
         has_else: bool
     },
 }
 
+impl Scope {
+    /// The keyword that starts the construct
+    fn opener(&self) -> &'static str {
+        match self {
+            Scope::While { .. } => "while",
+            Scope::Repeat { .. } => "repeat",
+            Scope::Loop { .. } => "loop",
+            Scope::Case { .. } => "case",
+            Scope::If { .. } => "if",
+        }
+    }
+
+    /// The keyword that ends the construct
+    fn terminator(&self) -> &'static str {
+        match self {
+            Scope::While { .. } => "endwhile",
+            Scope::Repeat { .. } => "endrepeat",
+            Scope::Loop { .. } => "endloop",
+            Scope::Case { .. } => "endcase",
+            Scope::If { .. } => "endif",
+        }
+    }
+
+    /// The line that the construct starts on
+    fn start_line(&self) -> usize {
+        match self {
+            Scope::While { start_line } | Scope::Repeat { start_line } | Scope::Loop { start_line } | Scope::Case { start_line, .. } => *start_line,
+            Scope::If { if_start_line, .. } => *if_start_line,
+        }
+    }
+
+    /// Gets the error for the construct not being ended before the end of the program
+    fn unterminated_error(&self) -> String {
+        format!("Error on line {}: '{}' is never ended, expected '{}' before the end of the program", self.start_line(), self.opener(), self.terminator())
+    }
+
+    /// Gets the error for a keyword on line_no that can't be used while this is the inner most construct
+    fn mismatch_error(&self, line_no: usize, keyword: &str) -> String {
+        format!("Error on line {line_no}: '{keyword}' does not match the '{}' on line {}, expected '{}'", self.opener(), self.start_line(), self.terminator())
+    }
+}
+
+/// The variables and constants used by a program
+#[derive(Debug, Default)]
+struct Symbols<'a> {
+    /// Definded variables, with their initial values.
+    /// Ordered so that the data section is emitted in the same order every time.
+    vars: BTreeMap<&'a str, i32>,
+    /// The lines each variable is assigned on, starting with the line it is defined on
+    defined_on: BTreeMap<&'a str, BTreeSet<usize>>,
+    /// Variables which are read somewhere in the program, with the lines they are read on
+    reads: BTreeMap<&'a str, BTreeSet<usize>>,
+    /// Variables assigned since the last change in control flow whose values haven't been read yet, with the line they were assigned on
+    unread_stores: BTreeMap<&'a str, usize>,
+    /// Constants used in expressions, as the LMC instruction set has no immediates,
+    /// with the lines that they are used on
+    consts: BTreeMap<i32, BTreeSet<usize>>,
+    /// Variables generated by the compiler which can't be referenced by the program, such as loop counters
+    hidden_vars: Vec<String>,
+    /// Variables declared with 'var', which only exist until the end of the block they are declared in
+    locals: Vec<Local<'a>>,
+    /// Variables declared with 'readonly', which can only be assigned to on the line they are declared on.
+    /// Variables defined in the options are declared on line 0, before the program
+    readonly: BTreeMap<&'a str, usize>,
+}
+
+/// A variable declared with 'var' inside a block
+#[derive(Debug, Clone, Copy)]
+struct Local<'a> {
+    name: &'a str,
+    /// The block the variable was declared in
+    frame: Scope,
+    /// The mailbox of the variable is local_{slot}, which is shared with locals in other blocks
+    slot: usize,
+}
+
+impl<'a> Symbols<'a> {
+    /// Records that the constant n is used on line_no, and gets its label.
+    /// All constants in the program should go through this function
+    fn intern_const(&mut self, n: i32, line_no: usize) -> String {
+        self.consts.entry(n).or_default().insert(line_no);
+        const_label(n)
+    }
+
+    /// Whether a variable can be used here
+    fn is_defined(&self, s: &str) -> bool {
+        self.vars.contains_key(s) || self.locals.iter().any(|l| l.name == s)
+    }
+
+    /// Gets the label of a variable.
+    /// A local variable hides any variable with the same name outside of its block
+    fn label(&self, s: &str) -> String {
+        match self.locals.iter().rev().find(|l| l.name == s) {
+            Some(local) => format!("local_{}", local.slot),
+            None => format!("var_{s}")
+        }
+    }
+
+    /// Creates a variable if it does not already exist, without overwriting its initial value if it does,
+    /// and records that it is assigned on line_no
+    fn define(&mut self, s: &'a str, line_no: usize) {
+        self.defined_on.entry(s).or_default().insert(line_no);
+        if self.locals.iter().any(|l| l.name == s) {
+            return;
+        }
+        self.vars.entry(s).or_insert(0);
+    }
+
+    /// Records that a variable is read on line_no
+    fn read(&mut self, s: &'a str, line_no: usize) {
+        self.reads.entry(s).or_default().insert(line_no);
+        self.unread_stores.remove(s);
+    }
+
+    /// Records that a variable is stored to on line_no, warning if the value it was given before is never read
+    fn store(&mut self, s: &'a str, line_no: usize, options: &CompileOptions, warnings: &mut Vec<Warning>) {
+        if let Some(previous) = self.unread_stores.insert(s, line_no) {
+            if options.warn_style && previous != line_no {
+                warnings.push(Warning { line: previous, message: format!("The value assigned to '{s}' is overwritten on line {line_no} before it is read") });
+            }
+        }
+    }
+
+    /// Errors if the variable is readonly and line_no is not where it is declared
+    fn check_assignable(&self, s: &str, line_no: usize) -> Result<(), String> {
+        // A local variable hides a readonly variable with the same name
+        if self.locals.iter().any(|l| l.name == s) {
+            return Ok(())
+        }
+        match self.readonly.get(s) {
+            Some(0) => Err(format!("Error on line {line_no}: Cannot assign to readonly variable '{s}', which is defined before the program")),
+            Some(&declared_on) if declared_on != line_no => Err(format!("Error on line {line_no}: Cannot assign to readonly variable '{s}', which is declared on line {declared_on}")),
+            _ => Ok(())
+        }
+    }
+
+    /// Declares a variable which only exists in the block frame.
+    /// It takes the first mailbox that isn't used by another local, so blocks which don't overlap share mailboxes
+    fn declare_local(&mut self, name: &'a str, frame: Scope, line_no: usize) -> Result<(), String> {
+        if self.locals.iter().any(|l| l.name == name && l.frame == frame) {
+            return Err(format!("Error on line {line_no}: Variable '{name}' is already declared in this block"))
+        }
+        let slot = (0..).find(|slot| self.locals.iter().all(|l| l.slot != *slot)).unwrap_or_default();
+        let label = format!("local_{slot}");
+        if !self.hidden_vars.contains(&label) {
+            self.hidden_vars.push(label);
+        }
+        self.locals.push(Local { name, frame, slot });
+        Ok(())
+    }
+
+    /// Forgets the local variables whose blocks have ended
+    fn end_blocks(&mut self, scope_stack: &[Scope]) {
+        self.locals.retain(|l| scope_stack.contains(&l.frame));
+    }
+
+    /// Gets the label of the variable or constant at line[index], for use as an operand in an expression
+    fn value_operand(&mut self, line: &[Token<'a>], index: usize) -> Result<String, String> {
+        let line_no = line[0].line;
+        match line.get(index) {
+            None => Err(format!("Error on line {line_no}: Expected identifier or number")),
+            Some(t) => match t.token_type {
+                Identifier(s) => {
+                    // Error if variable is not defined
+                    if !self.is_defined(s) {
+                        return Err(format!("Error on line {line_no} token {index}: Variable unknown identifier '{s}'"))
+                    }
+                    self.read(s, line_no);
+                    Ok(self.label(s))
+                },
+                Number(n) => {
+                    Ok(self.intern_const(n, line_no))
+                },
+                _ => Err(format!("Error on line {line_no} token {index}: Expected identifier or number, found '{}'", t.token_type))
+            }
+        }
+    }
+
+    /// Gets the label of the variable or constant at line[index], for use as an operand in a condition.
+    /// 'true' and 'false' are the constants 1 and 0.
+    /// 'input' reads a value into a hidden variable, emitting the code to do so to code.
+    fn condition_operand(&mut self, line: &[Token<'a>], index: usize, code: &mut String) -> Result<String, String> {
+        let line_no = line[0].line;
+        match line.get(index) {
+            None => Err(format!("Error on line {line_no}: Expected condition formed of two arguments and a comparison operator")),
+            Some(t) => match t.token_type {
+                Identifier(_) | Number(_) => self.value_operand(line, index),
+                True | False => {
+                    let n = (t.token_type == True) as i32;
+                    Ok(self.intern_const(n, line_no))
+                },
+                Input => {
+                    let temp = format!("input_{line_no}_{index}");
+                    *code += &format!("INP\nSTA {temp}\n");
+                    self.hidden_vars.push(temp.clone());
+                    Ok(temp)
+                },
+                _ => Err(format!("Error on line {line_no} token {index}: Expected identifier or number, found '{}'", t.token_type))
+            }
+        }
+    }
+
+    /// Gets the label of the operand at line[index] for a condition, like condition_operand, allowing a '-' before it.
+    /// A negated variable is worked out into a hidden variable, emitting the code to do so to code.
+    /// Returns the label and the index after the operand
+    fn signed_condition_operand(&mut self, line: &[Token<'a>], index: usize, code: &mut String) -> Result<(String, usize), String> {
+        let line_no = line[0].line;
+        if let Some(Token { token_type: OperatorSub, .. }) = line.get(index) {
+            // Negative numbers are just a different constant
+            if let Some(Token { token_type: Number(n), .. }) = line.get(index + 1) {
+                let n = negate(*n, line_no, index + 1)?;
+                return Ok((self.intern_const(n, line_no), index + 2));
+            }
+            let operand = self.condition_operand(line, index + 1, code)?;
+            let temp = format!("negate_{line_no}_{index}");
+            *code += &format!("LDA {}\nSUB {operand}\nSTA {temp}\n", self.intern_const(0, line_no));
+            self.hidden_vars.push(temp.clone());
+            return Ok((temp, index + 2));
+        }
+        Ok((self.condition_operand(line, index, code)?, index + 1))
+    }
+}
+
+/// Emits code to output the accumulator, followed by the separator if there is one.
+/// Outputting the separator overwrites the accumulator
+fn emit_output(options: &CompileOptions, symbols: &mut Symbols, line_no: usize) -> String {
+    match options.separator {
+        Some(c) => format!("OUT\nLDA {}\nOTC\n", symbols.intern_const(c as i32, line_no)),
+        None => "OUT\n".to_string()
+    }
+}
+
+/// Emits code to load the operand at line[index] into the accumulator.
+/// A '-' before it, like '-a', loads its negation instead.
+/// Returns the code and the index after the operand
+fn emit_load<'a>(line: &[Token<'a>], index: usize, symbols: &mut Symbols<'a>) -> Result<(String, usize), String> {
+    let line_no = line[0].line;
+    if let Some(Token { token_type: OperatorSub, .. }) = line.get(index) {
+        // Negative numbers are just a different constant
+        if let Some(Token { token_type: Number(n), .. }) = line.get(index + 1) {
+            let n = negate(*n, line_no, index + 1)?;
+            return Ok((format!("LDA {}\n", symbols.intern_const(n, line_no)), index + 2));
+        }
+        let operand = symbols.value_operand(line, index + 1)?;
+        return Ok((format!("LDA {}\nSUB {operand}\n", symbols.intern_const(0, line_no)), index + 2));
+    }
+    Ok((format!("LDA {}\n", symbols.value_operand(line, index)?), index + 1))
+}
+
+/// Emits code to add and subtract the terms starting at line[start], like '+ a - 5 + b', to the accumulator
+fn emit_terms<'a>(line: &[Token<'a>], start: usize, symbols: &mut Symbols<'a>) -> Result<String, String> {
+    let line_no = line[0].line;
+    let mut code = String::new();
+    let mut i = start;
+    while let Some(t) = line.get(i) {
+        let mut subtract = match t.token_type {
+            OperatorAdd => false,
+            OperatorSub => true,
+            _ => return Err(format!("Error on line {line_no} token {i}: Expected '+' or '-', found '{}'", t.token_type))
+        };
+        i += 1;
+
+        // A '-' before the operand, like 'a - -b', changes the operator
+        if let Some(Token { token_type: OperatorSub, .. }) = line.get(i) {
+            subtract = !subtract;
+            i += 1;
+        }
+
+        let instruction = if subtract { "SUB" } else { "ADD" };
+        code += &format!("{instruction} {}\n", symbols.value_operand(line, i)?);
+        i += 1;
+    }
+    Ok(code)
+}
+
+/// Gets the value of the condition starting at line[start], if it is just 'true' or 'false', or 'not' of one.
+/// Comparing a variable to itself also has the same value every time
+fn constant_condition(line: &[Token], start: usize) -> Option<bool> {
+    match line.get(start..)? {
+        [Token { token_type: True, .. }] => Some(true),
+        [Token { token_type: False, .. }] => Some(false),
+        [Token { token_type: Identifier(a), .. }, operator, Token { token_type: Identifier(b), .. }] if a == b => match operator.token_type {
+            OperatorEquality | OperatorGreaterThanInclusive | OperatorLessThanInclusive => Some(true),
+            OperatorInequality | OperatorGreaterThan | OperatorLessThan => Some(false),
+            _ => None
+        },
+        [Token { token_type: Not, .. }, ..] => constant_condition(line, start + 1).map(|value| !value),
+        _ => None
+    }
+}
+
+/// Emits code for the condition starting at line[start], for an if statement or while loop.
+/// The code branches to label_if_false if the condition is false, and either branches to label_if_true or falls through to the next instruction if it is true.
+/// A condition of just 'true' or 'false' is always or never taken, and needs no comparison.
+fn emit_condition<'a>(line: &[Token<'a>], start: usize, symbols: &mut Symbols<'a>, label_if_true: &str, label_if_false: &str) -> Result<String, String> {
+    let line_no = line[0].line;
+
+    // Constant conditions
+    if let Some(value) = constant_condition(line, start) {
+        // Variables compared to themselves still have to be defined
+        for (i, t) in line.iter().enumerate().skip(start) {
+            if let Identifier(_) = t.token_type {
+                symbols.value_operand(line, i)?;
+            }
+        }
+        return Ok(if value { String::new() } else { format!("BRA {label_if_false}\n") });
+    }
+
+    // 'not' is taken when the rest of the condition is false, so the labels swap.
+    // Non-constant conditions always branch to one of the labels, so there is no fall through to go the wrong way
+    if let Some(Token { token_type: Not, .. }) = line.get(start) {
+        return emit_condition(line, start + 1, symbols, label_if_false, label_if_true);
+    }
+
+    // Code to read any inputs used in the condition
+    let mut code = String::new();
+
+    let (mut lhs, terms_start) = symbols.signed_condition_operand(line, start, &mut code)?;
+
+    // Index of the comparison operator, which is after any terms added to the left hand side
+    let mut o = terms_start;
+    while let Some(Token { token_type: OperatorAdd | OperatorSub, .. }) = line.get(o) {
+        o += 1;
+        // A '-' before the operand, like 'a - -b'
+        if let Some(Token { token_type: OperatorSub, .. }) = line.get(o) {
+            o += 1;
+        }
+        o += 1;
+    }
+
+    // An expression on the left, like 'a + b < c', is worked out into a hidden variable first.
+    // This is part of the condition, so loops work it out again every time they check it
+    if o > terms_start {
+        let temp = format!("condition_{line_no}");
+        code += &format!("LDA {lhs}\n");
+        code += &emit_terms(&line[..o.min(line.len())], terms_start, symbols)?;
+        code += &format!("STA {temp}\n");
+        symbols.hidden_vars.push(temp.clone());
+        lhs = temp;
+    }
+
+    // 'x in lo hi' is true when lo <= x <= hi
+    if let Some(Token { token_type: In, .. }) = line.get(o) {
+        if let (Some(Token { token_type: Number(lo), .. }), Some(Token { token_type: Number(hi), .. })) = (line.get(o + 1), line.get(o + 2)) {
+            if lo > hi {
+                return Err(format!("Error on line {line_no} token {}: The range {lo} to {hi} is empty, the lower bound should come first", o + 1))
+            }
+        }
+
+        let (lo, hi_start) = symbols.signed_condition_operand(line, o + 1, &mut code)?;
+        let (hi, end) = symbols.signed_condition_operand(line, hi_start, &mut code)?;
+
+        // Error if too many tokens
+        if line.get(end).is_some() {
+            return Err(format!("Error on line {line_no} token {end}: Unexpected token after condition"))
+        }
+
+        // The upper bound is only checked if the value is above the lower bound
+        let label_above_lo = format!("{label_if_true}_in");
+        code += &format!("LDA {lhs}\nSUB {lo}\nBRP {label_above_lo}\nBRA {label_if_false}\n");
+        code += &format!("{label_above_lo} LDA {hi}\nSUB {lhs}\nBRP {label_if_true}\nBRA {label_if_false}\n");
+        return Ok(code);
+    }
+
+    // Check for a comparison operator before reading the right hand side
+    let operator = match line.get(o) {
+        Some(t) if is_comparison_operator(&t.token_type) => &t.token_type,
+        Some(t) => return Err(format!("Error on line {line_no} token {}: Expected comparison operator ('==', '!=', '>', '<', '>=' or '<=') between the two operands, found '{}'", o, t.token_type)),
+        None => return Err(format!("Error on line {line_no} token {}: Expected comparison operator ('==', '!=', '>', '<', '>=' or '<=') between the two operands", o))
+    };
+
+    let (rhs, end) = symbols.signed_condition_operand(line, o + 1, &mut code)?;
+
+    // Error if too many tokens
+    if line.get(end).is_some() {
+        return Err(format!("Error on line {line_no} token {end}: Unexpected token after condition"))
+    }
+
+    code += &match operator {
+        OperatorEquality => format!("LDA {lhs}\nSUB {rhs}\nBRZ {label_if_true}\nBRA {label_if_false}\n"),
+        OperatorInequality => format!("LDA {lhs}\nSUB {rhs}\nBRZ {label_if_false}\nBRA {label_if_true}\n"),
+
+        OperatorGreaterThan => format!("LDA {rhs}\nSUB {lhs}\nBRP {label_if_false}\nBRA {label_if_true}\n"),
+        OperatorLessThan => format!("LDA {lhs}\nSUB {rhs}\nBRP {label_if_false}\nBRA {label_if_true}\n"),
+
+        OperatorGreaterThanInclusive => format!("LDA {lhs}\nSUB {rhs}\nBRP {label_if_true}\nBRA {label_if_false}\n"),
+        OperatorLessThanInclusive => format!("LDA {rhs}\nSUB {lhs}\nBRP {label_if_true}\nBRA {label_if_false}\n"),
+
+        _ => return Err(format!("Error on line {line_no} token {}: Expected comparison operator", o))
+    };
+
+    Ok(code)
+}
+
 /// Parses a Vec<Token> into LMC assembly
-fn parse_tokens(src: Vec<Token>) -> Result<String, String> {
-    // Definded variables
-    let mut vars: HashMap<&str, i32> = HashMap::new();
-    // Constants used in expressions, as the LMC instruction set has no immediates
-    let mut consts: HashSet<i32> = HashSet::new();
-    // 0 is always a constant as a fix for having multiple labels on one line
-    consts.insert(0);
+fn parse_tokens<'a>(src: Vec<Token<'a>>, options: &'a CompileOptions, warnings: &mut Vec<Warning>) -> Result<Compiled, String> {
+    // Variables and constants
+    let mut symbols = Symbols::default();
+
+    // Defined values are readonly variables which exist before the first line
+    for (name, value) in &options.defines {
+        if !matches!(split_minus(name)[..], [s] if matches!(token_type(s), Identifier(_))) {
+            return Err(format!("Error: '{name}' can't be defined as it isn't a valid variable name"))
+        }
+        // The same bounds as numbers in the program, with the warning on line 0 as it is before the program
+        if !(-999..=999).contains(value) {
+            if options.strict {
+                return Err(format!("Error: The value {value} defined for '{name}' is outside the bounds of LMC numbers"))
+            }
+            warnings.push(Warning { line: 0, message: format!("The value {value} defined for '{name}' is outside the bounds of LMC numbers") });
+        }
+        symbols.vars.insert(name, *value);
+        symbols.readonly.insert(name, 0);
+    }
 
     // The program
     let mut program: String = String::new();
 
     // A stack of Scopes to store line numbers of constructs that need end labels
     let mut scope_stack: Vec<Scope> = Vec::new();
+
+    // The start lines of 'loop's which can be left with a 'break' or 'exit'
+    let mut ended_loops: HashSet<usize> = HashSet::new();
     
     // Loop line by line
     let lines: Vec<&[Token]> = src.split(|t| t.token_type == NewLine).collect();
 
     'lines: for line in lines {
         // Ignore empty lines
-        if line.len() == 0 {
+        if line.is_empty() {
             continue;
         }
         // Get line number in original text file of this line
         let line_no = line[0].line;
+
+        symbols.end_blocks(&scope_stack);
+
+        // Code between 'case' and the first 'when' would run whatever the value is
+        if let Some(Scope::Case { start_line, when_line: None, has_else: false }) = scope_stack.last() {
+            if !matches!(line[0].token_type, When | EndCase) {
+                return Err(format!("Error on line {line_no}: Expected 'when' after the 'case' on line {start_line}, found '{}'", line[0].token_type))
+            }
+        }
+
+        // A variable declared with 'var' on this line, with the block it is declared in.
+        // It is only declared after the value is worked out, so 'var x = x + 1' reads the 'x' outside of the block
+        let mut local: Option<(&str, Scope)> = None;
+
+        // 'var' declares a variable which only exists until the end of the block, and is otherwise an assignment.
+        // Outside of any block it is just a normal variable
+        let line = match line[0].token_type {
+            Var => {
+                match (line.get(1), line.get(2)) {
+                    (Some(Token { token_type: Identifier(s), .. }), Some(Token { token_type: OperatorAssignment, .. })) => {
+                        local = scope_stack.last().map(|&frame| (*s, frame));
+                    },
+                    _ => return Err(format!("Error on line {line_no}: Expected variable and '=' after 'var'"))
+                }
+                &line[1..]
+            },
+            // 'readonly' declares a variable which can't be assigned to after this line
+            Readonly => {
+                match (line.get(1), line.get(2)) {
+                    (Some(Token { token_type: Identifier(s), .. }), Some(Token { token_type: OperatorAssignment, .. })) => {
+                        // A value defined in the options replaces the one in the program
+                        if symbols.readonly.get(s) == Some(&0) {
+                            continue 'lines;
+                        }
+                        if symbols.is_defined(s) {
+                            return Err(format!("Error on line {line_no} token 1: Variable '{s}' is already defined, so it can't be made readonly"))
+                        }
+                        symbols.readonly.insert(s, line_no);
+                    },
+                    _ => return Err(format!("Error on line {line_no}: Expected variable and '=' after 'readonly'"))
+                }
+                &line[1..]
+            },
+            _ => line
+        };
+
+        // Any branch could read a stored value, so only straight line code can overwrite it unread
+        if !matches!(line[0].token_type, Identifier(_) | Input | Output | Rotate | Dump) {
+            symbols.unread_stores.clear();
+        }
+
         // Type of construct on line is determined by the first token
         match line[0].token_type {
             //Variable assignment
-            Identifier(assigned_to) => {
-                // Check for correct formatting
-                if line.len() == 1 || line[1].token_type != OperatorAssignment {
-                    return Err(format!("Error on line {line_no}: Identifer at the beginning of a line must be followed by '='"));
-                }
-
-                // Get left hand side of expression
-                match line.get(2) {
-                    // Error if line ends here
-                    None => return Err(format!("Error on line {line_no}: Expected identifier or number")),
-                    Some(t) => match t.token_type {
+            Identifier(_) => {
+                // Get the variables being assigned to, which are each followed by '='.
+                // For chained assignments like 'a = b = 5', the value is stored in all of them
+                let mut targets: Vec<&str> = Vec::new();
+                // Index of the start of the expression
+                let mut e = 0;
+                while let (Some(t), Some(Token { token_type: OperatorAssignment, .. })) = (line.get(e), line.get(e + 1)) {
+                    match t.token_type {
                         Identifier(s) => {
-                            // Error if variable is not defined
-                            if !vars.contains_key(s) {
-                                return Err(format!("Error on line {line_no} token 2: Variable unknown identifier '{s}'"))
+                            // The declared local hides any readonly variable with the same name
+                            if e > 0 || local.is_none() {
+                                symbols.check_assignable(s, line_no)?;
                             }
-                            // Emit code to load variable
-                            program += &format!("LDA var_{s}\n");
+                            targets.push(s)
                         },
-                        Number(n) => {
-                            // Optimisation for if a variable is initialised with a constant value
-                            if line.len() == 3 && !vars.contains_key(assigned_to) && scope_stack.len() == 0 {
-                                vars.insert(assigned_to, n);
-                                continue;
-                            }
-                            // Add const to set
-                            consts.insert(n);
-                            // Emit code to load const
-                            program += &format!("LDA const_{n}\n");
-                        }
-                        // If token is neither a variable or a number, error
-                        _ => return Err(format!("Error on line {line_no} token 2: Expected identifier or number"))
+                        _ => return Err(format!("Error on line {line_no} token {e}: Expected identifier to assign to, found '{}'", t.token_type))
                     }
+                    e += 2;
                 }
 
-                // Get operator
-                match line.get(3) {
-                    // If line ends here, just store data
-                    None => {
-                        vars.insert(assigned_to, 0);
-                        program += &format!("STA var_{assigned_to}\n");
-                        continue
-                    },
-                    // Else, emit partial code to perform calculation
-                    Some(t) => match t.token_type {
-                        OperatorAdd => program += "ADD ",
-                        OperatorSub => program += "SUB ",
-                        _ => return Err(format!("Error on line {line_no} token 3: Expected '+' or '-'"))
-                    }
+                // Check for correct formatting
+                if targets.is_empty() {
+                    return Err(format!("Error on line {line_no}: Identifer at the beginning of a line must be followed by '='"));
                 }
 
-                // Emit address of right hand side of expression
-                match line.get(4) {
-                    None => return Err(format!("Error on line {line_no}: Expected identifer or number")),
-                    Some(t) => match t.token_type {
-                        Identifier(s) => {
-                            // Error if variable is not defined
-                            if !vars.contains_key(s) {
-                                return Err(format!("Error on line {line_no} token 2: Unknown identifier '{s}'"))
-                            }
-                            // Emit code to load variable
-                            program += &format!("var_{s}\n");
-                        },
-                        Number(n) => {
-                            // Emit code to load const
-                            consts.insert(n);
-                            program += &format!("const_{n}\n")
-                        },
-                        _ => return Err(format!("Error on line {line_no} token 4: Expected identifer or number"))
+                // Optimisation for if variables are initialised with a constant value
+                if let (Some(Token { token_type: Number(n), .. }), None) = (line.get(e), line.get(e + 1)) {
+                    if targets.iter().all(|t| !symbols.is_defined(t)) && scope_stack.is_empty() {
+                        for t in targets {
+                            symbols.vars.insert(t, *n);
+                            symbols.defined_on.entry(t).or_default().insert(line_no);
+                            symbols.store(t, line_no, options, warnings);
+                        }
+                        continue;
                     }
                 }
-                // Emit code to store value
-                program += &format!("STA var_{assigned_to}\n");
 
-                // Error if too many tokens
-                if line.get(5).is_some() {
-                    return Err(format!("Error on line {line_no} token 5: Unexpected token"))
-                }
-                
-                // Create variable if it does not already exist
-                if !vars.contains_key(assigned_to) {
-                    vars.insert(assigned_to, 0);
+                // Emit code to load the left hand side of the expression
+                let (code, end) = emit_load(line, e, &mut symbols)?;
+                program += &code;
+
+                // Emit code for the rest of the expression, like '+ b - 1'
+                program += &emit_terms(line, end, &mut symbols)?;
+
+                if let Some((s, frame)) = local {
+                    let hides = symbols.is_defined(s);
+                    symbols.declare_local(s, frame, line_no)?;
+                    if hides && options.warn_style {
+                        warnings.push(Warning { line: line_no, message: format!("'var {s}' hides the variable with the same name outside of this block") });
+                    }
                 }
 
+                // Emit code to store value, from right to left
+                for t in targets.into_iter().rev() {
+                    symbols.store(t, line_no, options, warnings);
+                    symbols.define(t, line_no);
+                    program += &format!("STA {}\n", symbols.label(t));
+                }
             }
             //Input
             Input => {
                 // Find where to put inputted value
-                match line.get(1) {
+                let input_to = match line.get(1) {
                     None => return Err(format!("Error on line {line_no}: Expected identifier")),
                     Some(t) => match t.token_type {
-                        Identifier(s) => {
-                            // Create variable if it does not exist
-                            if !vars.contains_key(s) {
-                                vars.insert(s, 0);
-                            }
-                            // Emit code to input to variable
-                            program += &format!("INP\nSTA var_{s}\n")
-                        },
-                        _ => return Err(format!("Error on line {line_no} token 1: Expected identifier"))
+                        Identifier(s) => s,
+                        _ => return Err(format!("Error on line {line_no} token 1: Expected identifier, found '{}'", t.token_type))
                     }
-                }
+                };
 
-                // Error if too many
-                if line.get(2).is_some() {
-                    return Err(format!("Error on line {line_no} token 2: Unexpected token"))
-                }
+                program += "INP\n";
+
+                // Any terms after the variable adjust the value before it is stored
+                program += &emit_terms(line, 2, &mut symbols)?;
+
+                // Create variable if it does not exist
+                symbols.check_assignable(input_to, line_no)?;
+                symbols.store(input_to, line_no, options, warnings);
+                symbols.define(input_to, line_no);
+                // Emit code to store the input in the variable
+                program += &format!("STA {}\n", symbols.label(input_to));
             }
             //Output
             Output => {
-                match line.get(1) {
-                    None => return Err(format!("Error on line {line_no}: Expected identifier or number")),
-                    Some(t) => match t.token_type {
-                        Identifier(s) => {
-                            if !vars.contains_key(s) {
-                                return Err(format!("Error on line {line_no} token 2: Variable unknown identifier '{s}'"))
-                            }
-                            program += &format!("LDA var_{s}\n");
-                        },
-                        Number(n) => {
-                            consts.insert(n);
-                            program += &format!("LDA const_{n}\n");
-                        }
-                        _ => return Err(format!("Error on line {line_no} token 2: Expected identifier or number"))
-                    }
+                // With nothing to output, output whatever is left in the accumulator
+                if line.len() == 1 {
+                    program += &emit_output(options, &mut symbols, line_no);
+                    continue
                 }
 
-                match line.get(2) {
-                    None => {
-                        program += &format!("OUT\n");
-                        continue
-                    },
-                    Some(t) => match t.token_type {
-                        OperatorAdd => program += "ADD ",
-                        OperatorSub => program += "SUB ",
-                        _ => return Err(format!("Error on line {line_no} token 3: Expected '+' or '-'"))
-                    }
-                }
-
-                match line.get(3) {
-                    None => return Err(format!("Error on line {line_no}: Expected identifer or number")),
-                    Some(t) => match t.token_type {
-                        Identifier(s) => {
-                            // Error if variable is not defined
-                            if !vars.contains_key(s) {
-                                return Err(format!("Error on line {line_no} token 2: Variable unknown identifier '{s}'"))
-                            }
-                            // Emit code to load variable
-                            program += &format!("var_{s}\n");
-                        },
-                        Number(n) => {
-                            consts.insert(n);
-                            program += &format!("const_{n}\n")
-                        },
-                        _ => return Err(format!("Error on line {line_no} token 4: Expected identifer or number"))
-                    }
-                }
-                program += &format!("OUT\n");
-            }
-            //While
-            While => {
-                program += &format!("while_{line_no} ");
-                scope_stack.push(Scope::While { start_line: line_no });
-
+                let (code, end) = emit_load(line, 1, &mut symbols)?;
+                program += &code;
+                program += &emit_terms(line, end, &mut symbols)?;
+                program += &emit_output(options, &mut symbols, line_no);
+            }
+            //While
+            While => {
+                scope_stack.push(Scope::While { start_line: line_no });
 
                 let label_if_true = format!("while_{line_no}_body");
                 let label_if_false = format!("while_{line_no}_end");
 
-
-
-                let lhs = match line.get(1) {
-                    None => return Err(format!("Error on line {line_no}: Expected condition formed of two arguments and a comparison operator")),
-                    Some(t) => match t.token_type {
-                        Identifier(s) => {
-                            // Error if variable is not defined
-                            if !vars.contains_key(s) {
-                                return Err(format!("Error on line {line_no} token 2: Variable unknown identifier '{s}'"))
-                            }
-                            // Emit variable name
-                            format!("var_{s}\n")
-                        },
-                        Number(n) => {
-                            consts.insert(n);
-                            format!("const_{n}")
-                        },
-                        True => continue,
-                        _ => return Err(format!("Error on line {line_no} token 1: Expected identifier or number"))
-                    }
-                };
-
-                let rhs = match line.get(3) {
-                    None => return Err(format!("Error on line {line_no}: Expected condition formed of two arguments and a comparison operator")),
-                    Some(t) => match t.token_type {
-                        Identifier(s) => {
-                            // Error if variable is not defined
-                            if !vars.contains_key(s) {
-                                return Err(format!("Error on line {line_no} token 2: Variable unknown identifier '{s}'"))
-                            }
-                            // Emit code to load variable
-                            format!("var_{s}\n")
-                        },
-                        Number(n) => {
-                            consts.insert(n);
-                            format!("const_{n}")
-                        },
-                        _ => return Err(format!("Error on line {line_no} token 3: Expected identifier or number"))
-                    }
-                };
-
-                match line[2].token_type {
-                    OperatorEquality => program += &format!("LDA {lhs}\nSUB {rhs}\nBRZ {label_if_true}\nBRA {label_if_false}\n"),
-                    OperatorInequality => program += &format!("LDA {lhs}\nSUB {rhs}\nBRZ {label_if_false}\nBRA {label_if_true}\n"),
-
-                    OperatorGreaterThan => program += &format!("LDA {rhs}\nSUB {lhs}\nBRP {label_if_false}\nBRA {label_if_true}\n"),
-                    OperatorLessThan => program += &format!("LDA {lhs}\nSUB {rhs}\nBRP {label_if_false}\nBRA {label_if_true}\n"),
-
-                    OperatorGreaterThanInclusive => program += &format!("LDA {lhs}\nSUB {rhs}\nBRP {label_if_true}\nBRA {label_if_false}\n"),
-                    OperatorLessThanInclusive => program += &format!("LDA {rhs}\nSUB {lhs}\nBRP {label_if_true}\nBRA {label_if_false}\n"),
-                
-                    _ => return Err(format!("Error on line {line_no} token 2: Expected comparison operator"))
+                // 'while true' is the normal way to write an infinite loop, but a loop that never runs is probably a mistake
+                if constant_condition(line, 1) == Some(false) {
+                    warnings.push(Warning { line: line_no, message: "Condition is always false, so the loop never runs".to_string() });
                 }
 
-                program += &format!("{label_if_true} ");
+                let condition = emit_condition(line, 1, &mut symbols, &label_if_true, &label_if_false)?;
+                program += &format!("while_{line_no} {condition}{label_if_true} ");
             }
             //Break
             Break => {
+                expect_line_end(line, 1)?;
                 for frame in scope_stack.iter().rev() {
-                    if let Scope::While{start_line} = frame {
-                        program += &format!("BRA while_{start_line}_end\n");
-                        continue 'lines;
+                    match frame {
+                        Scope::While { start_line } => program += &format!("BRA while_{start_line}_end\n"),
+                        Scope::Repeat { start_line } => program += &format!("BRA repeat_{start_line}_end\n"),
+                        Scope::Loop { start_line } => {
+                            program += &format!("BRA loop_{start_line}_end\n");
+                            ended_loops.insert(*start_line);
+                        },
+                        _ => continue
                     }
+                    continue 'lines;
                 }
 
                 return Err(format!("Error on line {line_no}: 'break' while not in loop"));
             }
+            //Exit
+            Exit => {
+                // Output the exit value, if there is one
+                if line.len() > 1 {
+                    let (code, end) = emit_load(line, 1, &mut symbols)?;
+                    expect_line_end(line, end)?;
+                    program += &code;
+                    program += &emit_output(options, &mut symbols, line_no);
+                }
+
+                program += "HLT\n";
+
+                // Exiting ends all of the loops it is in
+                for frame in &scope_stack {
+                    if let Scope::Loop { start_line } = frame {
+                        ended_loops.insert(*start_line);
+                    }
+                }
+            }
+            //Loop
+            Loop => {
+                expect_line_end(line, 1)?;
+                scope_stack.push(Scope::Loop { start_line: line_no });
+                program += &format!("loop_{line_no} ");
+            }
+            //End loop
+            EndLoop => {
+                expect_line_end(line, 1)?;
+                match scope_stack.pop() {
+                    None => return Err(format!("Error on line {line_no}: 'endloop' without a matching 'loop'")),
+                    Some(Scope::Loop { start_line }) => {
+                        if !ended_loops.contains(&start_line) {
+                            warnings.push(Warning { line: start_line, message: "Loop has no 'break', so it never ends".to_string() });
+                        }
+                        program += &format!("BRA loop_{start_line}\nloop_{start_line}_end ");
+                    },
+                    Some(s) => return Err(s.mismatch_error(line_no, "endloop"))
+                }
+            }
             //End while
             EndWhile => {
+                expect_line_end(line, 1)?;
                 match scope_stack.pop() {
-                    None => return Err(format!("Error on line {line_no}: 'endwhile' found while 'while' loop was not inner most control flow construct")),
+                    None => return Err(format!("Error on line {line_no}: 'endwhile' without a matching 'while'")),
                     Some(Scope::While { start_line })=>  program += &format!("BRA while_{start_line}\nwhile_{start_line}_end "),
-                    _ => return Err(format!("Error on line {line_no}: 'endwhile' found while 'while' loop was not inner most control flow construct"))
+                    Some(s) => return Err(s.mismatch_error(line_no, "endwhile"))
+                }
+            }
+            //Repeat
+            Repeat => {
+                let counter = format!("repeat_{line_no}_count");
+
+                // A number of repetitions written in the program has to be positive
+                let not_positive = match (line.get(1).map(|t| &t.token_type), line.get(2).map(|t| &t.token_type)) {
+                    (None, _) => return Err(format!("Error on line {line_no}: Expected number of repetitions")),
+                    (Some(Number(n)), _) => *n <= 0,
+                    (Some(OperatorSub), Some(Number(n))) => *n >= 0,
+                    _ => false
+                };
+                if not_positive {
+                    return Err(format!("Error on line {line_no} token 1: Number of repetitions must be positive"))
+                }
+
+                // Emit code to initialise the counter
+                let (code, end) = emit_load(line, 1, &mut symbols)?;
+                expect_line_end(line, end)?;
+                program += &code;
+
+                // Exit when the counter reaches 0, otherwise decrement it and run the body
+                let one = symbols.intern_const(1, line_no);
+                program += &format!("STA {counter}\nrepeat_{line_no} LDA {counter}\nBRZ repeat_{line_no}_end\nSUB {one}\nSTA {counter}\n");
+
+                symbols.hidden_vars.push(counter);
+                scope_stack.push(Scope::Repeat { start_line: line_no });
+            }
+            //End repeat
+            EndRepeat => {
+                expect_line_end(line, 1)?;
+                match scope_stack.pop() {
+                    None => return Err(format!("Error on line {line_no}: 'endrepeat' without a matching 'repeat'")),
+                    Some(Scope::Repeat { start_line }) => program += &format!("BRA repeat_{start_line}\nrepeat_{start_line}_end "),
+                    Some(s) => return Err(s.mismatch_error(line_no, "endrepeat"))
                 }
             }
             //If
             If => {
                 scope_stack.push(Scope::If { if_start_line: line_no, else_start_line: line_no , has_else: false});
 
-                let lhs = match line.get(1) {
-                    None => return Err(format!("Error on line {line_no}: Expected condition formed of two arguments and a comparison operator")),
-                    Some(t) => match t.token_type {
-                        Identifier(s) => {
-                            // Error if variable is not defined
-                            if !vars.contains_key(s) {
-                                return Err(format!("Error on line {line_no} token 2: Variable unknown identifier '{s}'"))
-                            }
-                            // Emit code to load variable
-                            format!("var_{s}\n")
-                        },
-                        Number(n) => {
-                            consts.insert(n);
-                            format!("const_{n}")
-                        },
-                        _ => return Err(format!("Error on line {line_no} token 1: Expected identifier or number"))
-                    }
+                let label_if_true = format!("if_{line_no}_body");
+                let label_if_false = format!("if_{line_no}_else");
+
+                if let Some(value) = constant_condition(line, 1) {
+                    warnings.push(Warning { line: line_no, message: format!("Condition is always {value}") });
+                }
+
+                program += &emit_condition(line, 1, &mut symbols, &label_if_true, &label_if_false)?;
+                program += &format!("{label_if_true} ");
+            }
+            //Else
+            Else | Elif => {
+                // Index of the first token of the condition for an 'else if' or 'elif', or None for just 'else'
+                let condition_start = match (&line[0].token_type, line.get(1).map(|t| &t.token_type)) {
+                    (Elif, _) => Some(1),
+                    (Else, Some(If)) => Some(2),
+                    (Else, None) => None,
+                    (_, t) => return Err(format!("Error on line {line_no} token 1: Unexpected '{}' after 'else', expected 'else if' or just 'else'", t.unwrap_or(&NewLine)))
                 };
 
-                let rhs = match line.get(3) {
-                    None => return Err(format!("Error on line {line_no}: Expected condition formed of two arguments and a comparison operator")),
-                    Some(t) => match t.token_type {
-                        Identifier(s) => {
-                            // Error if variable is not defined
-                            if !vars.contains_key(s) {
-                                return Err(format!("Error on line {line_no} token 2: Variable unknown identifier '{s}'"))
-                            }
-                            // Emit code to load variable
-                            format!("var_{s}\n")
-                        },
-                        Number(n) => {
-                            consts.insert(n);
-                            format!("const_{n}")
-                        },
-                        _ => return Err(format!("Error on line {line_no} token 3: Expected identifier or number"))
+                let (if_start_line, else_start_line) = match scope_stack.pop() {
+                    None => return Err(format!("Error on line {line_no}: 'else' without a matching 'if'")),
+                    Some(Scope::If { if_start_line, has_else: true, .. }) => return Err(format!("Error on line {line_no}: '{}' after the 'else' of the 'if' on line {if_start_line}", line[0].token_type)),
+                    Some(Scope::If { if_start_line, else_start_line, has_else: false }) => (if_start_line, else_start_line),
+                    // The 'else' of a case runs if no 'when' matched
+                    Some(Scope::Case { start_line, when_line: Some(when_line), has_else: false }) if condition_start.is_none() => {
+                        program += &format!("BRA case_{start_line}_end\nwhen_{when_line}_next ");
+                        scope_stack.push(Scope::Case { start_line, when_line: Some(when_line), has_else: true });
+                        continue;
+                    },
+                    Some(Scope::Case { start_line, has_else: true, .. }) => return Err(format!("Error on line {line_no}: 'else' after the 'else' of the 'case' on line {start_line}")),
+                    Some(s) => return Err(s.mismatch_error(line_no, "else")),
+                };
+
+                // The previous branch jumps to the end, and the previous condition jumps here if false
+                program += &format!("BRA if_{if_start_line}_end\nif_{else_start_line}_else ");
+
+                let c = match condition_start {
+                    Some(c) => c,
+                    None => {
+                        scope_stack.push(Scope::If { if_start_line, else_start_line: line_no, has_else: true });
+                        continue;
                     }
                 };
 
+                scope_stack.push(Scope::If { if_start_line, else_start_line: line_no, has_else: false });
+
                 let label_if_true = format!("if_{line_no}_body");
                 let label_if_false = format!("if_{line_no}_else");
 
-                match line[2].token_type {
-                    OperatorEquality => program += &format!("LDA {lhs}\nSUB {rhs}\nBRZ {label_if_true}\nBRA {label_if_false}\n"),
-                    OperatorInequality => program += &format!("LDA {lhs}\nSUB {rhs}\nBRZ {label_if_false}\nBRA {label_if_true}\n"),
+                if let Some(value) = constant_condition(line, c) {
+                    warnings.push(Warning { line: line_no, message: format!("Condition is always {value}") });
+                }
+
+                program += &emit_condition(line, c, &mut symbols, &label_if_true, &label_if_false)?;
+                program += &format!("{label_if_true} ");
+            }
+            //End if
+            EndIf => {
+                expect_line_end(line, 1)?;
+                match scope_stack.pop() {
+                    None => return Err(format!("Error on line {line_no}: 'endif' without a matching 'if'")),
+                    Some(Scope::If { if_start_line, else_start_line, has_else }) => {
+                        // The last condition jumps here if it was false
+                        if !has_else {
+                            program += &format!("if_{else_start_line}_else ")
+                        }
+                        // Any 'else if' or 'else' means that the other branches jump here
+                        if has_else || else_start_line != if_start_line {
+                            program += &format!("if_{if_start_line}_end ")
+                        }
+                    }
+                    Some(s) => return Err(s.mismatch_error(line_no, "endif"))
+                }
+            }
+            //Case
+            Case => {
+                // The value is worked out once and kept for the 'when's to compare against
+                let value = symbols.value_operand(line, 1)?;
+                program += &format!("LDA {value}\n");
+                program += &emit_terms(line, 2, &mut symbols)?;
+                program += &format!("STA case_{line_no}\n");
 
-                    OperatorGreaterThan => program += &format!("LDA {rhs}\nSUB {lhs}\nBRP {label_if_false}\nBRA {label_if_true}\n"),
-                    OperatorLessThan => program += &format!("LDA {lhs}\nSUB {rhs}\nBRP {label_if_false}\nBRA {label_if_true}\n"),
+                symbols.hidden_vars.push(format!("case_{line_no}"));
+                scope_stack.push(Scope::Case { start_line: line_no, when_line: None, has_else: false });
+            }
+            //When
+            When => {
+                let (start_line, when_line) = match scope_stack.pop() {
+                    None => return Err(format!("Error on line {line_no}: 'when' without a matching 'case'")),
+                    Some(Scope::Case { start_line, has_else: true, .. }) => return Err(format!("Error on line {line_no}: 'when' after the 'else' of the 'case' on line {start_line}")),
+                    Some(Scope::Case { start_line, when_line, has_else: false }) => (start_line, when_line),
+                    Some(s) => return Err(s.mismatch_error(line_no, "when"))
+                };
 
-                    OperatorGreaterThanInclusive => program += &format!("LDA {lhs}\nSUB {rhs}\nBRP {label_if_true}\nBRA {label_if_false}\n"),
-                    OperatorLessThanInclusive => program += &format!("LDA {rhs}\nSUB {lhs}\nBRP {label_if_true}\nBRA {label_if_false}\n"),
-                
-                    _ => return Err(format!("Error on line {line_no} token 2: Expected comparison operator"))
+                // The previous branch jumps to the end, and the previous 'when' jumps here if it didn't match
+                if let Some(when_line) = when_line {
+                    program += &format!("BRA case_{start_line}_end\nwhen_{when_line}_next ");
                 }
 
-                program += &format!("{label_if_true} ");
+                let value = symbols.value_operand(line, 1)?;
+                expect_line_end(line, 2)?;
+                program += &format!("LDA case_{start_line}\nSUB {value}\nBRZ when_{line_no}\nBRA when_{line_no}_next\nwhen_{line_no} ");
+
+                scope_stack.push(Scope::Case { start_line, when_line: Some(line_no), has_else: false });
             }
-            //Else
-            Else => {
+            //End case
+            EndCase => {
+                expect_line_end(line, 1)?;
                 match scope_stack.pop() {
-                    None => return Err(format!("Error on line {line_no}: 'else' found while 'if' statement was not inner most control flow construct")),
-                    Some(Scope::If { if_start_line, else_start_line, has_else: _ }) => match line.get(1) {
-                        None => {
-                            scope_stack.push(Scope::If { if_start_line: if_start_line, else_start_line: line_no, has_else: true });
-                            program += &format!("BRA if_{if_start_line}_end\nif_{else_start_line}_else ");
-                        },
-                        Some(t) => match t.token_type {
-                            If => {
-                                scope_stack.push(Scope::If { if_start_line: if_start_line, else_start_line: line_no, has_else: true });
-
-                                let lhs = match line.get(2) {
-                                    None => return Err(format!("Error on line {line_no}: Expected condition formed of two arguments and a comparison operator")),
-                                    Some(t) => match t.token_type {
-                                        Identifier(s) => {
-                                            // Error if variable is not defined
-                                            if !vars.contains_key(s) {
-                                                return Err(format!("Error on line {line_no} token 2: Variable unknown identifier '{s}'"))
-                                            }
-                                            // Emit code to load variable
-                                            format!("var_{s}\n")
-                                        },
-                                        Number(n) => {
-                                            consts.insert(n);
-                                            format!("const_{n}")
-                                        },
-                                        _ => return Err(format!("Error on line {line_no} token 1: Expected identifier or number"))
-                                    }
-                                };
-
-                                let rhs = match line.get(4) {
-                                    None => return Err(format!("Error on line {line_no}: Expected condition formed of two arguments and a comparison operator")),
-                                    Some(t) => match t.token_type {
-                                        Identifier(s) => {
-                                            // Error if variable is not defined
-                                            if !vars.contains_key(s) {
-                                                return Err(format!("Error on line {line_no} token 2: Variable unknown identifier '{s}'"))
-                                            }
-                                            // Emit code to load variable
-                                            format!("var_{s}\n")
-                                        },
-                                        Number(n) => {
-                                            consts.insert(n);
-                                            format!("const_{n}")
-                                        },
-                                        _ => return Err(format!("Error on line {line_no} token 3: Expected identifier or number"))
-                                    }
-                                };
-
-                                let label_if_true = format!("if_{line_no}_body");
-                                let label_if_false = format!("if_{line_no}_else");
-
-                                program += &format!("BRA if_{if_start_line}_end\nif_{else_start_line}_else ");
-
-                                match line[3].token_type {
-                                    OperatorEquality => program += &format!("LDA {lhs}\nSUB {rhs}\nBRZ {label_if_true}\nBRA {label_if_false}\n"),
-                                    OperatorInequality => program += &format!("LDA {lhs}\nSUB {rhs}\nBRZ {label_if_false}\nBRA {label_if_true}\n"),
-
-                                    OperatorGreaterThan => program += &format!("LDA {rhs}\nSUB {lhs}\nBRP {label_if_false}\nBRA {label_if_true}\n"),
-                                    OperatorLessThan => program += &format!("LDA {lhs}\nSUB {rhs}\nBRP {label_if_false}\nBRA {label_if_true}\n"),
-
-                                    OperatorGreaterThanInclusive => program += &format!("LDA {lhs}\nSUB {rhs}\nBRP {label_if_true}\nBRA {label_if_false}\n"),
-                                    OperatorLessThanInclusive => program += &format!("LDA {rhs}\nSUB {lhs}\nBRP {label_if_true}\nBRA {label_if_false}\n"),
-                                
-                                    _ => return Err(format!("Error on line {line_no} token 2: Expected comparison operator"))
-                                }
-
-                                program += &format!("{label_if_true} ");
-                            },
-                            _ => return Err(format!("Error on line {line_no}: 'else' found while 'if' statement was not inner most control flow construct"))
+                    None => return Err(format!("Error on line {line_no}: 'endcase' without a matching 'case'")),
+                    Some(Scope::Case { start_line, when_line, has_else }) => {
+                        // The last 'when' jumps here if it didn't match
+                        if let (Some(when_line), false) = (when_line, has_else) {
+                            program += &format!("when_{when_line}_next ");
                         }
+                        program += &format!("case_{start_line}_end ");
                     },
-                    s => {
-                        println!("{:?}", s);
-                        println!("{:?}", scope_stack);
-                        return Err(format!("Error on line {line_no}: expected 'else if' or just 'else'"))}
+                    Some(s) => return Err(s.mismatch_error(line_no, "endcase"))
                 }
             }
-            //End if
-            EndIf => {
-                match scope_stack.pop() {
-                    None => return Err(format!("Error on line {line_no}: 'endif' found while 'if' statement was not inner most control flow construct")),
-                    Some(Scope::If { if_start_line, else_start_line: _, has_else }) => {
-                        if has_else {
-                            program += &format!("if_{if_start_line}_end ADD const_0\n")
+            //Assembly
+            Asm => {
+                let text = match line.get(1) {
+                    Some(Token { token_type: Str(text), .. }) => text,
+                    _ => return Err(format!("Error on line {line_no}: Expected an LMC instruction in quotes after 'asm', like asm \"LDA x\""))
+                };
+                expect_line_end(line, 2)?;
+
+                // Only check that it is one instruction with the right number of operands, the rest is up to the programmer
+                let instruction = match assembly::split_line(text) {
+                    Some(l) if l.label.is_none() && assembly::is_mnemonic(l.mnemonic) && text.split_whitespace().count() <= 2 => l,
+                    _ => return Err(format!("Error on line {line_no} token 1: \"{text}\" is not an LMC instruction, expected an instruction like \"LDA x\" without a label"))
+                };
+                // Data in the middle of the code would be run as an instruction
+                if instruction.mnemonic == "DAT" {
+                    return Err(format!("Error on line {line_no} token 1: 'asm' can't be used for DAT, as it would be run as an instruction"))
+                }
+                let takes_operand = !matches!(instruction.mnemonic, "INP" | "OUT" | "OTC" | "HLT");
+                match (takes_operand, instruction.operand) {
+                    (true, None) => return Err(format!("Error on line {line_no} token 1: \"{text}\" needs an operand")),
+                    (false, Some(_)) => return Err(format!("Error on line {line_no} token 1: \"{text}\" doesn't take an operand")),
+                    _ => {}
+                }
+
+                program += instruction.mnemonic;
+                if let Some(operand) = instruction.operand {
+                    // Variables of the program can be used by name, anything else is passed through as it is
+                    if symbols.is_defined(operand) {
+                        // Storing to a variable is an assignment, so it follows the same rules
+                        if instruction.mnemonic == "STA" {
+                            symbols.check_assignable(operand, line_no)?;
+                            symbols.store(operand, line_no, options, warnings);
+                            symbols.define(operand, line_no);
                         }
                         else {
-                            program += &format!("if_{if_start_line}_else ADD const_0\n")
+                            symbols.read(operand, line_no);
                         }
+                        program += &format!(" {}", symbols.label(operand));
+                    }
+                    else {
+                        program += &format!(" {operand}");
                     }
-                    _ => return Err(format!("Error on line {line_no}: 'endif' found while 'if' statement was not inner most control flow construct"))
                 }
+                // The optimiser leaves the instruction as it is
+                program += &format!(" {}\n", assembly::VERBATIM);
+            }
+            //Rotate
+            Rotate => {
+                let mut vars: Vec<&str> = Vec::new();
+                for (i, t) in line.iter().enumerate().skip(1) {
+                    match t.token_type {
+                        Identifier(s) if symbols.is_defined(s) => {
+                            symbols.check_assignable(s, line_no)?;
+                            vars.push(s)
+                        },
+                        Identifier(s) => return Err(format!("Error on line {line_no} token {i}: Variable unknown identifier '{s}'")),
+                        _ => return Err(format!("Error on line {line_no} token {i}: Expected variable to rotate, found '{}'", t.token_type))
+                    }
+                }
+                if vars.len() < 2 {
+                    return Err(format!("Error on line {line_no}: Expected at least two variables to rotate"))
+                }
+                for &s in &vars {
+                    symbols.read(s, line_no);
+                    symbols.define(s, line_no);
+                }
+
+                // Each variable takes the value of the one after it, and the last takes the value of the first,
+                // which is kept in a temporary as it is overwritten first
+                let temp = format!("rotate_{line_no}");
+                program += &format!("LDA {}\nSTA {temp}\n", symbols.label(vars[0]));
+                for pair in vars.windows(2) {
+                    program += &format!("LDA {}\nSTA {}\n", symbols.label(pair[1]), symbols.label(pair[0]));
+                }
+                program += &format!("LDA {temp}\nSTA {}\n", symbols.label(vars[vars.len() - 1]));
+                symbols.hidden_vars.push(temp);
+            }
+            //Dump
+            Dump => {
+                expect_line_end(line, 1)?;
+
+                // Only the variables defined before this line are known, in order of name then local variables in order of declaration.
+                // A local hides a variable with the same name, so that is only output once
+                let names: Vec<&str> = symbols.vars.keys().copied().chain(symbols.locals.iter().map(|l| l.name)).collect();
+                let mut labels: Vec<String> = Vec::new();
+                for name in names {
+                    let label = symbols.label(name);
+                    if !labels.contains(&label) {
+                        symbols.read(name, line_no);
+                        program += &format!("LDA {label}\n");
+                        program += &emit_output(options, &mut symbols, line_no);
+                        labels.push(label);
+                    }
+                }
+            }
+            //Numbers can't be assigned to
+            Number(_) => return Err(format!("Error on line {line_no}: Cannot assign to a number literal")),
+            //Operators can't start a line
+            OperatorAdd | OperatorSub | OperatorAssignment | OperatorEquality | OperatorInequality |
+            OperatorGreaterThan | OperatorLessThan | OperatorGreaterThanInclusive | OperatorLessThanInclusive => {
+                return Err(format!("Error on line {line_no}: Unexpected operator at start of line; did you mean to assign to a variable?"))
             }
             
-            _ => return Err(format!("Error on line {line_no}: Expected assignment, input, output, or start or end of if statement or while loop"))
+            _ => return Err(format!("Error on line {line_no}: Expected assignment, input, output, exit, or start or end of if statement, case statement, while loop, repeat loop or loop, found '{}'", line[0].token_type))
+        }
+    }
+
+    // The inner most construct is the one that is missing its end
+    if let Some(scope) = scope_stack.last() {
+        return Err(scope.unterminated_error())
+    }
+
+    if options.warn_unused {
+        for (s, lines) in &symbols.defined_on {
+            if !symbols.reads.contains_key(s) {
+                warnings.push(Warning { line: lines.first().copied().unwrap_or_default(), message: format!("Variable '{s}' is never read") });
+            }
         }
     }
 
-    program += "HLT\n\n";
-    for (s, n) in vars {
-        program += &format!("var_{s} DAT {n}\n");
+    // The program only needs to halt at the end if it can get there.
+    // Any label waiting for an instruction means something could branch to the end
+    let ends_in_jump = program.ends_with('\n') && program.lines().last()
+        .and_then(assembly::split_line)
+        .is_some_and(|l| matches!(l.mnemonic, "HLT" | "BRA"));
+    if !ends_in_jump {
+        program += "HLT\n";
     }
 
+    // Labels for the same place, like the end of nested constructs, are emitted next to each other,
+    // which the optimiser merges
+    program = assembly::optimise(&program);
     program += "\n";
-    for n in consts {
-        program += &format!("const_{n} DAT {n}\n");
+
+    // Labels used by instructions, so that data which is never used can be left out.
+    // Variables which are only inputted to are still used by the STA
+    let referenced: HashSet<String> = program.lines()
+        .filter_map(assembly::split_line)
+        .filter_map(|l| l.operand.map(str::to_string))
+        .collect();
+
+    for (s, n) in &symbols.vars {
+        let label = format!("var_{s}");
+        if referenced.contains(&label) {
+            program += &format!("{label} DAT {n}\n");
+        }
+    }
+    for s in &symbols.hidden_vars {
+        if referenced.contains(s) {
+            program += &format!("{s} DAT 0\n");
+        }
+    }
+    let hidden_vars = symbols.hidden_vars;
+
+    program += "\n";
+    for &n in symbols.consts.keys() {
+        let label = const_label(n);
+        if referenced.contains(&label) {
+            program += &format!("{label} DAT {n}\n");
+        }
     }
 
-    Ok(program)
+    // Variables, constants and the compiler's own labels have different prefixes, so they should never clash
+    if let Some(label) = assembly::find_duplicate_label(&program) {
+        return Err(format!("Error: The label '{label}' is used for two different things, try renaming the variable it comes from"))
+    }
+
+    // Each instruction and each piece of data takes one mailbox
+    let cells = program.lines().filter_map(assembly::split_line).count();
+    if cells > options.max_cells {
+        return Err(format!("Error: The program needs {cells} mailboxes, which is more than the limit of {}", options.max_cells))
+    }
+
+    let program = assembly::strip_verbatim(&program);
+
+    // Every variable, including ones which are only read, like defined values
+    let mut variables: BTreeMap<String, (BTreeSet<usize>, BTreeSet<usize>)> = BTreeMap::new();
+    for s in symbols.vars.keys().chain(symbols.defined_on.keys()).chain(symbols.reads.keys()) {
+        let assigned = symbols.defined_on.get(s).cloned().unwrap_or_default();
+        let read = symbols.reads.get(s).cloned().unwrap_or_default();
+        variables.insert(s.to_string(), (assigned, read));
+    }
+
+    Ok(Compiled { assembly: program, constants: symbols.consts, variables, hidden_vars })
+}
+
+/// Errors if there are any warnings and the options make them errors
+fn fail_on_warnings(options: &CompileOptions, warnings: &[Warning]) -> Result<(), String> {
+    if options.warnings_as_errors && !warnings.is_empty() {
+        return Err(format!("Error: Warnings are treated as errors, and there were {}", warnings.len()))
+    }
+    Ok(())
 }
 
-pub fn compile(src: &str) -> Result<String, String> {
+/// Compiles a program to LMC assembly.
+/// Any warnings are added to warnings, even if compilation fails.
+pub fn compile(src: &str, options: &CompileOptions, warnings: &mut Vec<Warning>) -> Result<Compiled, String> {
+    let previous_warnings = warnings.len();
+
+    check_quotes(src, &options.comment_markers)?;
+
+    let tokens = tokenise(src, &options.comment_markers);
 
-    let tokens = tokenise(src);
+    check_number_bounds(&tokens, options, warnings)?;
 
-    parse_tokens(tokens)
+    let mut compiled = parse_tokens(tokens, options, warnings)?;
 
-}
\ No newline at end of file
+    fail_on_warnings(options, &warnings[previous_warnings..])?;
+
+    compiled.assembly = assembly::rename_labels(&compiled.assembly, options.target);
+    compiled.assembly = assembly::format_dialect(&compiled.assembly, options.dialect);
+
+    Ok(compiled)
+
+}
+
+/// Checks a program for errors, for feedback in editors.
+/// The assembly is still generated, as some errors like running out of mailboxes depend on it,
+/// but the labels aren't renamed and it isn't laid out for the dialect.
+/// Gives the same error as compile would, and adds the same warnings to warnings.
+pub fn check(src: &str, options: &CompileOptions, warnings: &mut Vec<Warning>) -> Result<(), String> {
+    let previous_warnings = warnings.len();
+    check_quotes(src, &options.comment_markers)?;
+    let tokens = tokenise(src, &options.comment_markers);
+    check_number_bounds(&tokens, options, warnings)?;
+    parse_tokens(tokens, options, warnings)?;
+    fail_on_warnings(options, &warnings[previous_warnings..])
+}
+
+/// Finds style problems in a program without printing the assembly, for use in editors.
+/// These are all of the warnings the compiler can give: numbers out of bounds, conditions which are always true or false,
+/// unused variables, values which are overwritten before they are read, and 'var's which hide another variable.
+/// Errors stop the analysis, but the warnings found before them are still added to warnings.
+pub fn lint(src: &str, options: &CompileOptions, warnings: &mut Vec<Warning>) -> Result<(), String> {
+    let options = CompileOptions { warn_unused: true, warn_style: true, ..options.clone() };
+    check(src, &options, warnings)
+}
+
+/// Lays out a program with consistent indentation and spacing, keeping comments.
+/// The bodies of if statements and loops are indented by four spaces, and tokens are separated by one space.
+/// Does not error - the program is laid out even if it wouldn't compile.
+pub fn format_source(src: &str, comment_markers: &[String]) -> String {
+    let mut formatted = String::new();
+    // The number of blocks the line is in
+    let mut depth: usize = 0;
+
+    for line in src.lines() {
+        let (code, comment) = split_comment(line.trim_end_matches('\r'), comment_markers);
+        // Spacing is only changed outside of quotes, so strings are kept as they are.
+        // Splitting on quotes gives text outside of quotes at even indexes and inside them at odd indexes
+        let parts: Vec<&str> = code.split('"').collect();
+        let mut words: Vec<String> = Vec::new();
+        for (i, part) in parts.iter().enumerate() {
+            if i % 2 == 0 {
+                words.extend(part.split_whitespace().map(str::to_string));
+            }
+            // A string which isn't closed is left without a closing quote
+            else if i == parts.len() - 1 {
+                words.push(format!("\"{part}"));
+            }
+            else {
+                words.push(format!("\"{part}\""));
+            }
+        }
+        let first = words.first().map(|w| token_type(w));
+
+        // The ends of blocks and 'else's line up with the start of the block
+        if matches!(first, Some(EndIf | EndWhile | EndRepeat | EndLoop | EndCase | Else | Elif | When)) {
+            depth = depth.saturating_sub(1);
+        }
+
+        let mut text = words.join(" ");
+        if let Some(comment) = comment {
+            if !text.is_empty() {
+                text += " ";
+            }
+            text += comment.trim_end();
+        }
+        // Blank lines are kept, without indentation
+        if !text.is_empty() {
+            formatted += &"    ".repeat(depth);
+            formatted += &text;
+        }
+        formatted += "\n";
+
+        if matches!(first, Some(If | While | Repeat | Loop | Case | Else | Elif | When)) {
+            depth += 1;
+        }
+    }
+    formatted
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    /// Compiles a program with the options, giving the assembly or the error
+    fn compile_with(src: &str, options: &CompileOptions) -> Result<String, String> {
+        compile(src, options, &mut Vec::new()).map(|compiled| compiled.assembly)
+    }
+
+    /// Compiles a program with the default options, panicking if it doesn't compile
+    fn compile_ok(src: &str) -> String {
+        compile_with(src, &CompileOptions::default()).expect("Program should compile")
+    }
+
+    /// Compiles a program with the default options, panicking if it compiles
+    fn compile_err(src: &str) -> String {
+        compile_with(src, &CompileOptions::default()).expect_err("Program should not compile")
+    }
+
+    /// Runs assembly on a simple Little Man Computer with the given inputs, and gets the values it outputs.
+    /// Values aren't wrapped to three digits, so negative numbers behave as they do in the source.
+    /// Panics if the program doesn't halt, so that broken loops fail the test
+    fn run(assembly: &str, inputs: &[i32]) -> Vec<i32> {
+        let lines: Vec<assembly::Line> = assembly.lines().filter_map(assembly::split_line).collect();
+        let addresses: HashMap<&str, usize> = lines.iter().enumerate()
+            .filter_map(|(i, l)| l.label.map(|label| (label, i)))
+            .collect();
+        let address = |line: &assembly::Line| -> usize {
+            let operand = line.operand.expect("Instruction should have an operand");
+            addresses.get(operand).copied().unwrap_or_else(|| operand.parse().expect("Operand should be a label or an address"))
+        };
+
+        let mut memory: Vec<i32> = lines.iter()
+            .map(|l| if l.mnemonic == "DAT" { l.operand.map_or(0, |n| n.parse().expect("DAT should have a number")) } else { 0 })
+            .collect();
+        memory.resize(memory.len().max(100), 0);
+
+        let mut inputs = inputs.iter();
+        let mut outputs = Vec::new();
+        let mut accumulator = 0;
+        let mut counter = 0;
+        for _ in 0..100_000 {
+            let line = lines[counter];
+            counter += 1;
+            match line.mnemonic {
+                "LDA" => accumulator = memory[address(&line)],
+                "STA" => memory[address(&line)] = accumulator,
+                "ADD" => accumulator += memory[address(&line)],
+                "SUB" => accumulator -= memory[address(&line)],
+                "INP" => accumulator = *inputs.next().expect("Program should only read the inputs it is given"),
+                "OUT" => outputs.push(accumulator),
+                "OTC" => {},
+                "BRA" => counter = address(&line),
+                "BRZ" => if accumulator == 0 { counter = address(&line) },
+                "BRP" => if accumulator >= 0 { counter = address(&line) },
+                "HLT" => return outputs,
+                m => panic!("Unexpected instruction {m}")
+            }
+        }
+        panic!("Program should have halted")
+    }
+
+    /// Counts the instructions in the assembly with the mnemonic and operand
+    fn count_instruction(assembly: &str, mnemonic: &str, operand: &str) -> usize {
+        assembly.lines().filter_map(assembly::split_line).filter(|l| l.mnemonic == mnemonic && l.operand == Some(operand)).count()
+    }
+
+    #[test]
+    fn dead_store_is_removed() {
+        let assembly = compile_ok("input a\nx = a\nx = a + 1\nprint x");
+        assert_eq!(count_instruction(&assembly, "STA", "var_x"), 1);
+        assert_eq!(run(&assembly, &[4]), vec![5]);
+    }
+
+    #[test]
+    fn store_read_by_loop_is_kept() {
+        let assembly = compile_ok("input a\nx = a\nwhile x > 0\n    x = x - 1\n    print x\nendwhile");
+        assert_eq!(count_instruction(&assembly, "STA", "var_x"), 2);
+        assert_eq!(run(&assembly, &[3]), vec![2, 1, 0]);
+    }
+
+    #[test]
+    fn branch_to_next_instruction_is_removed() {
+        let assembly = compile_ok("input a\nwhile a > 0\n    a = a - 1\nendwhile\nprint a");
+        assert_eq!(count_instruction(&assembly, "BRA", "while_2_body"), 0);
+        assert_eq!(run(&assembly, &[3]), vec![0]);
+    }
+
+    #[test]
+    fn empty_if_body_falls_through() {
+        let assembly = compile_ok("input a\nif a > 0\nendif\nprint a");
+        assert_eq!(run(&assembly, &[5]), vec![5]);
+        assert_eq!(run(&assembly, &[-5]), vec![-5]);
+    }
+
+    #[test]
+    fn empty_else_body_falls_through() {
+        let assembly = compile_ok("input a\nif a > 0\n    print 1\nelse\nendif\nprint a");
+        assert_eq!(run(&assembly, &[5]), vec![1, 5]);
+        assert_eq!(run(&assembly, &[-5]), vec![-5]);
+    }
+
+    #[test]
+    fn value_just_stored_is_not_reloaded() {
+        let assembly = compile_ok("input x\nx = x - 1\nif x == 0\n    print 1\nendif");
+        assert_eq!(count_instruction(&assembly, "LDA", "var_x"), 0);
+        assert_eq!(run(&assembly, &[1]), vec![1]);
+        assert_eq!(run(&assembly, &[2]), vec![]);
+    }
+
+    #[test]
+    fn missing_comparison_operator_is_an_error() {
+        assert!(compile_err("a = 1\nwhile a b\nendwhile").contains("Expected comparison operator"));
+        assert!(compile_err("a = 1\nwhile a\nendwhile").contains("Expected comparison operator"));
+        assert!(compile_err("while\nendwhile").starts_with("Error on line 1"));
+    }
+
+    #[test]
+    fn chained_assignment_sets_every_variable() {
+        let assembly = compile_ok("a = b = 5\nprint a\nprint b");
+        assert_eq!(run(&assembly, &[]), vec![5, 5]);
+
+        let assembly = compile_ok("input x\na = b = x + 1\nprint a\nprint b");
+        assert_eq!(run(&assembly, &[2]), vec![3, 3]);
+    }
+
+    #[test]
+    fn chained_assignment_to_number_is_an_error() {
+        assert!(compile_err("a = 5 = b").contains("Expected identifier to assign to"));
+    }
+
+    #[test]
+    fn malformed_else_if_is_an_error() {
+        let start = "a = 1\nb = 2\nif a > b\n";
+        assert!(compile_err(&format!("{start}else if a\nendif")).starts_with("Error on line 4"));
+        assert!(compile_err(&format!("{start}else if a >\nendif")).starts_with("Error on line 4"));
+        assert!(compile_err(&format!("{start}else if a > b extra\nendif")).contains("Unexpected token after condition"));
+    }
+
+    #[test]
+    fn local_variable_after_its_block_is_an_error() {
+        assert!(compile_err("input a\nif a > 0\n    var t = 1\nendif\nprint t").contains("unknown identifier 't'"));
+    }
+
+    #[test]
+    fn local_variables_in_separate_blocks_share_a_mailbox() {
+        let assembly = compile_ok("input a\nif a > 0\n    var t = a + 1\n    print t\nendif\nif a < 5\n    var u = a + 2\n    print u\nendif");
+        assert!(!assembly.contains("local_1"));
+        assert_eq!(run(&assembly, &[3]), vec![4, 5]);
+    }
+
+    #[test]
+    fn local_variable_initialiser_reads_outer_variable() {
+        let assembly = compile_ok("x = 1\ninput a\nif a == 1\n    var x = x + 1\n    output x\nendif\noutput x");
+        assert_eq!(run(&assembly, &[1]), vec![2, 1]);
+    }
+
+    #[test]
+    fn output_of_several_terms() {
+        let assembly = compile_ok("input a\ninput b\ninput c\noutput a + b + c\noutput a - b + c - 1");
+        assert_eq!(run(&assembly, &[1, 2, 3]), vec![6, 1]);
+    }
+
+    #[test]
+    fn case_runs_first_matching_when() {
+        let assembly = compile_ok("input a\ncase a\nwhen 1\n    print 10\nwhen 2\n    print 20\nelse\n    print 0\nendcase\nprint 99");
+        assert_eq!(run(&assembly, &[1]), vec![10, 99]);
+        assert_eq!(run(&assembly, &[2]), vec![20, 99]);
+        assert_eq!(run(&assembly, &[3]), vec![0, 99]);
+    }
+
+    #[test]
+    fn case_without_else_skips_to_end() {
+        let assembly = compile_ok("input a\ncase a + 1\nwhen 2\n    print 20\nendcase\nprint 99");
+        assert_eq!(run(&assembly, &[1]), vec![20, 99]);
+        assert_eq!(run(&assembly, &[5]), vec![99]);
+    }
+
+    #[test]
+    fn readonly_variable_can_be_read() {
+        let assembly = compile_ok("readonly r = 3\nprint r + 1");
+        assert_eq!(run(&assembly, &[]), vec![4]);
+    }
+
+    #[test]
+    fn readonly_variable_cannot_be_assigned() {
+        assert!(compile_err("readonly r = 3\nr = 4").contains("Cannot assign to readonly variable 'r'"));
+        assert!(compile_err("readonly r = 3\ninput r").contains("Cannot assign to readonly variable 'r'"));
+    }
+
+    #[test]
+    fn out_of_bounds_number_is_a_warning() {
+        let mut warnings = Vec::new();
+        assert!(compile("print 1500", &CompileOptions::default(), &mut warnings).is_ok());
+        assert_eq!(warnings, vec![Warning { line: 1, message: "Number 1500 is outside the bounds of LMC numbers".to_string() }]);
+    }
+
+    #[test]
+    fn out_of_bounds_number_is_an_error_in_strict_mode() {
+        let options = CompileOptions { strict: true, ..CompileOptions::default() };
+        assert_eq!(compile_with("print 1500", &options), Err("Error on line 1: Number 1500 is outside the bounds of LMC numbers".to_string()));
+    }
+
+    #[test]
+    fn short_target_labels_are_short_and_distinct() {
+        let src = "input a\nb = 2\nwhile a > 0\n    if a == b\n        print 100\n    endif\n    a = a - 1\nendwhile\nrepeat 2\n    print b\nendrepeat";
+        let options = CompileOptions { target: Target::Short, ..CompileOptions::default() };
+        let assembly = compile_with(src, &options).expect("Program should compile");
+
+        assert_eq!(assembly::find_duplicate_label(&assembly), None);
+        let lines: Vec<assembly::Line> = assembly.lines().filter_map(assembly::split_line).collect();
+        for label in lines.iter().filter_map(|l| l.label) {
+            let (prefix, counter) = label.split_at(1);
+            assert!(matches!(prefix, "v" | "c" | "l") && counter.parse::<usize>().is_ok(), "Label '{label}' should be short");
+        }
+        assert_eq!(run(&assembly, &[3]), run(&compile_ok(src), &[3]));
+    }
+
+    #[test]
+    fn configured_comment_marker_replaces_defaults() {
+        let options = CompileOptions { comment_markers: vec![";".to_string()], ..CompileOptions::default() };
+        let assembly = compile_with("x = 1 ; a note\nprint x", &options).expect("Program should compile");
+        assert_eq!(run(&assembly, &[]), vec![1]);
+        assert!(compile_with("x = 1 // a note", &options).is_err());
+    }
+
+    #[test]
+    fn several_comment_markers() {
+        let options = CompileOptions { comment_markers: vec![";".to_string(), "--".to_string()], ..CompileOptions::default() };
+        let assembly = compile_with("x = 1 ; a note\n-- another note\nprint x", &options).expect("Program should compile");
+        assert_eq!(run(&assembly, &[]), vec![1]);
+    }
+
+    #[test]
+    fn warnings_as_errors_fails_on_warning() {
+        let src = "print 1500";
+        assert!(compile_with(src, &CompileOptions::default()).is_ok());
+        let options = CompileOptions { warnings_as_errors: true, ..CompileOptions::default() };
+        assert_eq!(compile_with(src, &options), Err("Error: Warnings are treated as errors, and there were 1".to_string()));
+    }
+
+    #[test]
+    fn negative_initial_value_is_signed_dat() {
+        let assembly = compile_ok("x = -5\nprint x");
+        assert!(assembly.contains("var_x DAT -5"));
+        assert_eq!(run(&assembly, &[]), vec![-5]);
+    }
+
+    #[test]
+    fn smallest_number_is_only_a_warning() {
+        let mut warnings = Vec::new();
+        let compiled = compile("output -2147483648", &CompileOptions::default(), &mut warnings).expect("Program should compile");
+        assert!(compiled.assembly.contains("const_neg2147483648 DAT -2147483648"));
+        assert_eq!(warnings.len(), 1);
+    }
+
+    #[test]
+    fn signed_operands_in_assignments() {
+        let assembly = compile_ok("input a\ninput b\nx = a + -5\ny = a - -b\nz = -a\nprint x\nprint y\nprint z");
+        assert_eq!(run(&assembly, &[7, 2]), vec![2, 9, -7]);
+    }
+
+    #[test]
+    fn signed_operands_in_conditions() {
+        let assembly = compile_ok("input a\ninput b\nif -a < 3\n    print 1\nendif\nif a + -b < 3\n    print 2\nendif\nif a > -b\n    print 3\nendif");
+        assert_eq!(run(&assembly, &[1, 5]), vec![1, 2, 3]);
+        assert_eq!(run(&assembly, &[-5, -1]), vec![2]);
+    }
+
+    #[test]
+    fn negating_smallest_number_is_an_error() {
+        assert!(compile_err("x = - -2147483648").contains("can't be negated"));
+        assert!(compile_err("input a\nif a < - -2147483648\nendif").contains("can't be negated"));
+    }
+
+    #[test]
+    fn lint_reports_several_categories() {
+        let src = "x = 1\nx = 2\nprint x\ny = 1500\ninput a\nif a == a\nendif\nif a > 0\n    var x = 3\n    print x\nendif";
+        let mut warnings = Vec::new();
+        assert!(lint(src, &CompileOptions::default(), &mut warnings).is_ok());
+        let mut lines: Vec<usize> = warnings.iter().map(|w| w.line).collect();
+        lines.sort();
+        assert_eq!(lines, vec![1, 4, 4, 6, 9]);
+        assert!(warnings.iter().any(|w| w.message.contains("overwritten on line 2")));
+        assert!(warnings.iter().any(|w| w.message.contains("hides the variable")));
+        assert!(warnings.iter().any(|w| w.message == "Variable 'y' is never read"));
+    }
+
+    #[test]
+    fn lint_uses_options_and_reports_errors() {
+        let options = CompileOptions { comment_markers: vec![";".to_string()], ..CompileOptions::default() };
+        let mut warnings = Vec::new();
+        assert!(lint("x = 1 ; note", &options, &mut warnings).is_ok());
+        assert_eq!(warnings.len(), 1);
+        assert!(lint("y = 3\nfoo bar", &CompileOptions::default(), &mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn asm_is_not_optimised() {
+        let assembly = compile_ok("asm \"STA 50\"\nasm \"LDA 50\"\nasm \"HLT\"");
+        assert_eq!(assembly.trim(), "STA 50\nLDA 50\nHLT");
+    }
+
+    #[test]
+    fn asm_numeric_operand_is_not_renamed() {
+        let options = CompileOptions { target: Target::Short, ..CompileOptions::default() };
+        let assembly = compile_with("x = 3\nasm \"LDA 5\"\nasm \"ADD x\"", &options).expect("Program should compile");
+        assert!(assembly.starts_with("LDA 5\nADD v0\n"));
+    }
+
+    #[test]
+    fn separator_after_each_output() {
+        let options = CompileOptions { separator: Some(' '), ..CompileOptions::default() };
+        let assembly = compile_with("print 1\nprint 2\nexit 3", &options).expect("Program should compile");
+        assert_eq!(count_instruction(&assembly, "LDA", "const_32"), 3);
+        assert_eq!(assembly.lines().filter(|l| l.trim() == "OTC").count(), 3);
+        assert_eq!(run(&assembly, &[]), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn defined_value_out_of_bounds() {
+        let defines = vec![("MAX".to_string(), 5000)];
+        let options = CompileOptions { defines: defines.clone(), ..CompileOptions::default() };
+        let mut warnings = Vec::new();
+        assert!(compile("print MAX", &options, &mut warnings).is_ok());
+        assert_eq!(warnings.len(), 1);
+
+        let options = CompileOptions { defines, strict: true, ..CompileOptions::default() };
+        assert!(compile_with("print MAX", &options).is_err());
+    }
+
+    #[test]
+    fn defined_value_can_be_used() {
+        let options = CompileOptions { defines: vec![("MAX".to_string(), 4)], ..CompileOptions::default() };
+        let assembly = compile_with("readonly MAX = 10\nprint MAX + 2", &options).expect("Program should compile");
+        assert_eq!(run(&assembly, &[]), vec![6]);
+    }
+
+    #[test]
+    fn long_chained_assignment_compiles() {
+        let targets: Vec<String> = (0..10_000).map(|i| format!("v{i}")).collect();
+        let src = format!("input q\n{} = q", targets.join(" = "));
+        let options = CompileOptions { max_cells: 100_000, ..CompileOptions::default() };
+
+        let assembly = compile_with(&src, &options).expect("Program should compile");
+        // One store for the input, and one for each target
+        assert_eq!(assembly.lines().filter(|l| l.starts_with("STA")).count(), 10_001);
+    }
+
+    #[test]
+    fn check_gives_same_result_as_compile() {
+        let options = CompileOptions { max_cells: 5, ..CompileOptions::default() };
+        for src in ["print 1", "foo bar", "input a\nif a > 0\nprint a", "repeat 3\nprint 1\nendrepeat"] {
+            let mut check_warnings = Vec::new();
+            let mut compile_warnings = Vec::new();
+            let checked = check(src, &options, &mut check_warnings);
+            let compiled = compile(src, &options, &mut compile_warnings).map(|_| ());
+            assert_eq!(checked, compiled);
+            assert_eq!(check_warnings, compile_warnings);
+        }
+    }
+
+    #[test]
+    fn else_after_final_else_is_an_error() {
+        let start = "input a\nif a > 0\n    print a\nelse\n    print 5\n";
+        assert_eq!(compile_err(&format!("{start}else\n    print 6\nendif")), "Error on line 6: 'else' after the 'else' of the 'if' on line 2");
+        assert_eq!(compile_err(&format!("{start}elif a == 0\n    print 6\nendif")), "Error on line 6: 'elif' after the 'else' of the 'if' on line 2");
+        assert_eq!(compile_err(&format!("{start}else if a == 0\n    print 6\nendif")), "Error on line 6: 'else' after the 'else' of the 'if' on line 2");
+    }
+
+    #[test]
+    fn asm_checks_operands() {
+        assert!(compile_err("asm \"DAT 5\"").contains("can't be used for DAT"));
+        assert!(compile_err("asm \"LDA\"").contains("needs an operand"));
+        assert!(compile_err("asm \"OUT 5\"").contains("doesn't take an operand"));
+        assert!(compile_ok("asm \"INP\"\nasm \"OUT\"").starts_with("INP\nOUT\n"));
+    }
+
+    #[test]
+    fn unclosed_string_is_an_error() {
+        assert_eq!(compile_err("x = 1\nasm \"LDA x"), "Error on line 2: Expected a '\"' to close the string");
+        assert!(compile_ok("asm \"LDA 5\" // a \" in a comment").starts_with("LDA 5\n"));
+    }
+
+    #[test]
+    fn asm_store_to_readonly_is_an_error() {
+        assert!(compile_err("readonly r = 3\nasm \"STA r\"").contains("Cannot assign to readonly variable 'r'"));
+        assert!(compile_ok("r = 3\ninput a\nasm \"STA r\"\nprint r").contains("STA var_r"));
+    }
+
+    #[test]
+    fn exit_outputs_value_and_halts() {
+        let assembly = compile_ok("exit 3\nprint 4");
+        assert_eq!(run(&assembly, &[]), vec![3]);
+
+        let assembly = compile_ok("input a\nexit -a");
+        assert_eq!(run(&assembly, &[2]), vec![-2]);
+    }
+
+    #[test]
+    fn bare_exit_only_halts() {
+        assert_eq!(compile_ok("exit").trim(), "HLT");
+        assert!(compile_err("exit 3 4").contains("Unexpected '4' after '3'"));
+    }
+
+    #[test]
+    fn format_source_lays_out_messy_program() {
+        let messy = "input   a\n  if a >  0 //positive\nasm   \"LDA    a\"\n      while a > 0\na = a  -  1\n endwhile\n\nelse\nprint 0\n   endif\n";
+        let expected = "input a\nif a > 0 //positive\n    asm \"LDA    a\"\n    while a > 0\n        a = a - 1\n    endwhile\n\nelse\n    print 0\nendif\n";
+        let markers = CompileOptions::default().comment_markers;
+        let formatted = format_source(messy, &markers);
+        assert_eq!(formatted, expected);
+        assert_eq!(format_source(&formatted, &markers), formatted);
+    }
+
+    #[test]
+    fn list_identifiers_shows_assignments_and_reads() {
+        let compiled = compile("input a\nprint a\nprint a + 1\nrepeat 2\nendrepeat", &CompileOptions::default(), &mut Vec::new()).expect("Program should compile");
+        assert_eq!(compiled.list_identifiers(), "a assigned on line 1 and read on lines 2, 3\nconst_1 used on lines 3, 4\nconst_2 used on line 4\nrepeat_4_count made by the compiler\n");
+    }
+
+    #[test]
+    fn variables_named_like_constants_dont_collide() {
+        let assembly = compile_ok("input neg5\nconst_5 = neg5 - 5\nprint const_5");
+        assert_eq!(assembly::find_duplicate_label(&assembly), None);
+        assert!(assembly.contains("var_neg5 DAT 0") && assembly.contains("var_const_5 DAT 0") && assembly.contains("const_5 DAT 5"));
+        assert_eq!(run(&assembly, &[7]), vec![2]);
+    }
+
+    #[test]
+    fn token_iterator_matches_tokenise() {
+        let markers = CompileOptions::default().comment_markers;
+        let src = "input a // comment\nif a > -5\n    asm \"LDA a\"\nendif\n";
+        assert_eq!(tokens(src, &markers).collect::<Vec<_>>(), tokenise(src, &markers));
+
+        let first: Vec<TokenType> = tokens(src, &markers).take(3).map(|t| t.token_type).collect();
+        assert_eq!(first, vec![Input, Identifier("a"), NewLine]);
+    }
+
+    #[test]
+    fn loop_of_only_break_exits() {
+        let assembly = compile_ok("while true\n    break\nendwhile\nprint 1");
+        assert_eq!(run(&assembly, &[]), vec![1]);
+    }
+
+    #[test]
+    fn break_inside_if_leaves_loop() {
+        let assembly = compile_ok("a = 0\nwhile true\n    a = a + 1\n    if a == 3\n        break\n    endif\n    print a\nendwhile\nprint 100");
+        assert_eq!(run(&assembly, &[]), vec![1, 2, 100]);
+    }
+
+    #[test]
+    fn loop_until_negative_constant() {
+        let assembly = compile_ok("input x\nwhile x != -1\n    print x\n    input x\nendwhile");
+        assert!(assembly.contains("const_neg1 DAT -1"));
+        assert!(!assembly.contains("const_0"));
+        assert_eq!(run(&assembly, &[3, 0, -2, -1]), vec![3, 0, -2]);
+    }
+
+    #[test]
+    fn empty_loop_body_ends() {
+        let assembly = compile_ok("while input > 0\nendwhile\nprint 1");
+        assert_eq!(run(&assembly, &[5, 2, 0]), vec![1]);
+    }
+
+    /// Gets the types of the tokens for a program
+    fn token_types<'a>(src: &'a str, comment_markers: &'a [String]) -> Vec<TokenType<'a>> {
+        tokenise(src, comment_markers).into_iter().map(|t| t.token_type).collect()
+    }
+
+    #[test]
+    fn comment_only_lines_are_empty() {
+        let markers = CompileOptions::default().comment_markers;
+        assert_eq!(token_types("//\n// just a comment\n# another", &markers), vec![NewLine, NewLine, NewLine]);
+    }
+
+    #[test]
+    fn whitespace_only_lines_are_empty() {
+        let markers = CompileOptions::default().comment_markers;
+        assert_eq!(token_types("   \n\t \t\n", &markers), vec![NewLine, NewLine]);
+    }
+
+    #[test]
+    fn long_identifier_is_one_token() {
+        let markers = CompileOptions::default().comment_markers;
+        let name = "x".repeat(10_000);
+        let src = format!("{name} = 1");
+        assert_eq!(token_types(&src, &markers), vec![Identifier(&name), OperatorAssignment, Number(1), NewLine]);
+        assert!(compile_ok(&format!("input {name}\nprint {name}")).contains(&format!("var_{name} DAT 0")));
+    }
+
+    #[test]
+    fn empty_file_just_halts() {
+        let markers = CompileOptions::default().comment_markers;
+        assert_eq!(token_types("", &markers), vec![]);
+        assert_eq!(compile_ok("").trim(), "HLT");
+    }
+
+    #[test]
+    fn crlf_line_endings_match_keywords() {
+        let markers = CompileOptions::default().comment_markers;
+        assert_eq!(token_types("input a\r\nendif\r\n", &markers), vec![Input, Identifier("a"), NewLine, EndIf, NewLine]);
+        let assembly = compile_ok("input a\r\nif a > 0 // positive\r\n    print a\r\nendif\r\n");
+        assert_eq!(run(&assembly, &[2]), vec![2]);
+    }
+
+    #[test]
+    fn repeated_constant_has_one_dat() {
+        let assembly = compile_ok("input a\na = a + 1\nprint a + 1\nb = a - 1\nprint b");
+        assert_eq!(assembly.lines().filter(|l| l.trim() == "const_1 DAT 1").count(), 1);
+        assert_eq!(count_instruction(&assembly, "ADD", "const_1"), 2);
+        assert_eq!(run(&assembly, &[4]), vec![6, 4]);
+    }
+
+    #[test]
+    fn repeat_runs_body_that_many_times() {
+        let assembly = compile_ok("x = 0\nrepeat 3\n    x = x + 1\n    print x\nendrepeat");
+        assert_eq!(run(&assembly, &[]), vec![1, 2, 3]);
+        let assembly = compile_ok("input n\nrepeat n\n    print 7\nendrepeat");
+        assert_eq!(run(&assembly, &[2]), vec![7, 7]);
+        assert_eq!(run(&assembly, &[0]), vec![]);
+    }
+
+    #[test]
+    fn break_leaves_repeat() {
+        let assembly = compile_ok("x = 0\nrepeat 10\n    x = x + 1\n    if x == 3\n        break\n    endif\n    print x\nendrepeat\nprint 100");
+        assert_eq!(run(&assembly, &[]), vec![1, 2, 100]);
+    }
+
+    #[test]
+    fn wrong_terminator_names_the_open_block() {
+        assert_eq!(compile_err("repeat 2\nendwhile"), "Error on line 2: 'endwhile' does not match the 'repeat' on line 1, expected 'endrepeat'");
+        assert_eq!(compile_err("loop\n    break\nendwhile"), "Error on line 3: 'endwhile' does not match the 'loop' on line 1, expected 'endloop'");
+        assert_eq!(compile_err("x = 1\nwhile x > 0\nendrepeat"), "Error on line 3: 'endrepeat' does not match the 'while' on line 2, expected 'endwhile'");
+        assert_eq!(compile_err("x = 1\ncase x\nwhen 1\nendloop"), "Error on line 4: 'endloop' does not match the 'case' on line 2, expected 'endcase'");
+        assert_eq!(compile_err("x = 1\nif x > 0\nendcase"), "Error on line 3: 'endcase' does not match the 'if' on line 2, expected 'endif'");
+        assert_eq!(compile_err("x = 1\nwhile x > 0\n    if x == 1\n    endwhile\nendif"), "Error on line 4: 'endwhile' does not match the 'if' on line 3, expected 'endif'");
+    }
+
+    #[test]
+    fn in_includes_both_bounds() {
+        let assembly = compile_ok("input x\nif x in 1 10\n    print 1\nelse\n    print 0\nendif");
+        for (x, expected) in [(0, 0), (1, 1), (10, 1), (11, 0)] {
+            assert_eq!(run(&assembly, &[x]), vec![expected], "for x = {x}");
+        }
+    }
+
+    #[test]
+    fn reversed_in_range_is_an_error() {
+        assert_eq!(compile_err("input x\nif x in 10 1\nendif"), "Error on line 2 token 3: The range 10 to 1 is empty, the lower bound should come first");
+    }
+
+    #[test]
+    fn not_inverts_condition() {
+        let assembly = compile_ok("input x\nif not x > 5\n    print 1\nelse\n    print 0\nendif");
+        assert_eq!(run(&assembly, &[3]), vec![1]);
+        assert_eq!(run(&assembly, &[5]), vec![1]);
+        assert_eq!(run(&assembly, &[6]), vec![0]);
+        let assembly = compile_ok("input x\nwhile not x == 0\n    print x\n    x = x - 1\nendwhile");
+        assert_eq!(run(&assembly, &[2]), vec![2, 1]);
+    }
+
+    #[test]
+    fn not_of_constant_condition() {
+        let mut warnings = Vec::new();
+        let assembly = compile("if not false\n    print 1\nendif", &CompileOptions::default(), &mut warnings).unwrap().assembly;
+        assert_eq!(run(&assembly, &[]), vec![1]);
+        assert_eq!(warnings, vec![Warning { line: 1, message: "Condition is always true".to_string() }]);
+    }
+
+    #[test]
+    fn rotate_moves_each_value_along() {
+        let assembly = compile_ok("input a\ninput b\ninput c\nrotate a b c\nprint a\nprint b\nprint c");
+        assert_eq!(run(&assembly, &[1, 2, 3]), vec![2, 3, 1]);
+        let assembly = compile_ok("input a\ninput b\nrotate a b\nprint a\nprint b");
+        assert_eq!(run(&assembly, &[1, 2]), vec![2, 1]);
+    }
+
+    #[test]
+    fn rotate_needs_known_variables() {
+        assert_eq!(compile_err("input a\nrotate a"), "Error on line 2: Expected at least two variables to rotate");
+        assert_eq!(compile_err("input a\nrotate a b"), "Error on line 2 token 2: Variable unknown identifier 'b'");
+        assert_eq!(compile_err("input a\nrotate a 5"), "Error on line 2 token 2: Expected variable to rotate, found '5'");
+    }
+
+    #[test]
+    fn loop_without_break_is_a_warning() {
+        let mut warnings = Vec::new();
+        assert!(compile("x = 1\nloop\n    print x\nendloop", &CompileOptions::default(), &mut warnings).is_ok());
+        assert_eq!(warnings, vec![Warning { line: 2, message: "Loop has no 'break', so it never ends".to_string() }]);
+    }
+
+    #[test]
+    fn loop_with_break_or_exit_ends() {
+        let mut warnings = Vec::new();
+        let assembly = compile("input x\nloop\n    if x == 0\n        break\n    endif\n    print x\n    x = x - 1\nendloop", &CompileOptions::default(), &mut warnings).unwrap().assembly;
+        assert!(warnings.is_empty());
+        assert_eq!(run(&assembly, &[3]), vec![3, 2, 1]);
+        let assembly = compile("input x\nloop\n    if x == 0\n        exit 9\n    endif\n    x = x - 1\nendloop", &CompileOptions::default(), &mut warnings).unwrap().assembly;
+        assert!(warnings.is_empty());
+        assert_eq!(run(&assembly, &[2]), vec![9]);
+    }
+
+    #[test]
+    fn dump_outputs_each_variable() {
+        let assembly = compile_ok("input b\ninput a\ndump");
+        assert_eq!(assembly.lines().filter(|l| l.trim() == "OUT").count(), 2);
+        assert_eq!(run(&assembly, &[1, 2]), vec![2, 1]);
+        let assembly = compile_ok("input a\nif a > 0\n    var a = 5\n    dump\nendif");
+        assert_eq!(run(&assembly, &[1]), vec![5]);
+    }
+
+    #[test]
+    fn variable_compared_to_itself_is_constant() {
+        let mut warnings = Vec::new();
+        let assembly = compile("input x\nif x == x\n    print 1\nendif\nif x != x\n    print 2\nendif", &CompileOptions::default(), &mut warnings).unwrap().assembly;
+        assert_eq!(run(&assembly, &[4]), vec![1]);
+        assert_eq!(warnings, vec![
+            Warning { line: 2, message: "Condition is always true".to_string() },
+            Warning { line: 5, message: "Condition is always false".to_string() },
+        ]);
+        assert_eq!(compile_err("if y == y\nendif"), "Error on line 1 token 1: Variable unknown identifier 'y'");
+    }
+
+    #[test]
+    fn while_condition_sum_is_worked_out_each_time() {
+        let assembly = compile_ok("input a\ninput b\ninput limit\nwhile a + b < limit\n    print a\n    a = a + 1\nendwhile");
+        assert_eq!(run(&assembly, &[1, 2, 6]), vec![1, 2, 3]);
+        assert_eq!(run(&assembly, &[5, 2, 6]), vec![]);
+    }
+
+    #[test]
+    fn input_in_condition_reads_each_time() {
+        let assembly = compile_ok("if input == 5\n    print 1\nelse\n    print 0\nendif");
+        assert_eq!(run(&assembly, &[5]), vec![1]);
+        assert_eq!(run(&assembly, &[4]), vec![0]);
+        let assembly = compile_ok("x = 0\nwhile input != 0\n    x = x + 1\nendwhile\nprint x");
+        assert_eq!(run(&assembly, &[3, 8, 0]), vec![2]);
+    }
+
+    #[test]
+    fn true_and_false_conditions_branch() {
+        let assembly = compile_ok("if true\n    print 1\nelse\n    print 0\nendif\nif false\n    print 2\nelse\n    print 3\nendif");
+        assert_eq!(run(&assembly, &[]), vec![1, 3]);
+        let assembly = compile_ok("x = 0\nwhile true\n    x = x + 1\n    if x == 3\n        break\n    endif\nendwhile\nprint x");
+        assert_eq!(run(&assembly, &[]), vec![3]);
+        let assembly = compile_ok("input x\nif x == true\n    print 1\nendif");
+        assert_eq!(run(&assembly, &[1]), vec![1]);
+        assert_eq!(run(&assembly, &[0]), vec![]);
+    }
+
+    #[test]
+    fn input_terms_adjust_value() {
+        let assembly = compile_ok("input x + 1\nprint x\ninput y - x + 10\nprint y");
+        assert_eq!(run(&assembly, &[4, 2]), vec![5, 7]);
+        assert_eq!(compile_err("input x 1"), "Error on line 1 token 2: Expected '+' or '-', found '1'");
+    }
+
+    #[test]
+    fn output_negated_variable() {
+        let assembly = compile_ok("input x\noutput -x\nprint -x + 10\nprint -5");
+        assert_eq!(run(&assembly, &[3]), vec![-3, 7, -5]);
+        assert_eq!(run(&assembly, &[-4]), vec![4, 14, -5]);
+    }
+
+    #[test]
+    fn bare_output_outputs_accumulator() {
+        let assembly = compile_ok("input a\ninput b\nx = a + b\noutput\nprint x");
+        assert_eq!(run(&assembly, &[2, 3]), vec![5, 5]);
+    }
+
+    #[test]
+    fn elif_is_the_same_as_else_if() {
+        let body = "\n    print 1\n{}\n    print 2\nelse\n    print 3\nendif";
+        let with_elif = compile_ok(&format!("input a\nif a > 5{}", body.replace("{}", "elif a > 2")));
+        let with_else_if = compile_ok(&format!("input a\nif a > 5{}", body.replace("{}", "else if a > 2")));
+        assert_eq!(with_elif, with_else_if);
+        for (a, expected) in [(6, 1), (3, 2), (1, 3)] {
+            assert_eq!(run(&with_elif, &[a]), vec![expected]);
+        }
+    }
+
+    #[test]
+    fn variables_start_at_initial_value_or_zero() {
+        let assembly = compile_ok("y = 7\ninput x\nprint x + y");
+        let data: Vec<&str> = assembly.lines().filter(|l| l.contains("DAT")).collect();
+        assert_eq!(data, vec!["var_x DAT 0", "var_y DAT 7"]);
+        assert_eq!(compile_ok("input x\ny = 7\nprint x + y"), assembly);
+    }
+
+    #[test]
+    fn folded_constant_has_no_dat() {
+        let assembly = compile_ok("x = 5\nprint x");
+        assert!(assembly.contains("var_x DAT 5"));
+        assert!(!assembly.contains("const_5"));
+        let assembly = compile_ok("input unused\nprint 1");
+        assert!(assembly.contains("var_unused DAT 0"));
+    }
+
+    #[test]
+    fn final_halt_only_when_reachable() {
+        let count_halts = |assembly: &str| assembly.lines().filter_map(assembly::split_line).filter(|l| l.mnemonic == "HLT").count();
+        let assembly = compile_ok("input x\nprint x\nexit");
+        assert!(assembly.starts_with("INP\nSTA var_x\nOUT\nHLT\n\n"));
+        assert_eq!(count_halts(&assembly), 1);
+        let assembly = compile_ok("input x\nloop\n    if x == 0\n        break\n    endif\n    x = x - 1\nendloop");
+        assert_eq!(count_halts(&assembly), 1);
+        assert_eq!(run(&assembly, &[2]), vec![]);
+        let assembly = compile_ok("input x\nif x > 0\n    exit 1\nendif");
+        assert_eq!(count_halts(&assembly), 2);
+        assert_eq!(run(&assembly, &[0]), vec![]);
+    }
+
+    #[test]
+    fn tokens_after_bare_keyword_are_an_error() {
+        assert_eq!(compile_err("input x\nif x > 0\nendif x"), "Error on line 3 token 1: Unexpected 'x' after 'endif'");
+        assert_eq!(compile_err("input x\nwhile x > 0\n    x = x - 1\nendwhile x"), "Error on line 4 token 1: Unexpected 'x' after 'endwhile'");
+        assert_eq!(compile_err("input x\nloop\n    break x\nendloop"), "Error on line 3 token 1: Unexpected 'x' after 'break'");
+    }
+
+    #[test]
+    fn end_without_start_is_an_error() {
+        assert_eq!(compile_err("endif"), "Error on line 1: 'endif' without a matching 'if'");
+        assert_eq!(compile_err("x = 1\nprint x\nendwhile"), "Error on line 3: 'endwhile' without a matching 'while'");
+    }
+
+    #[test]
+    fn unterminated_block_is_an_error() {
+        assert_eq!(compile_err("input a\nif a > 0\n    print a"), "Error on line 2: 'if' is never ended, expected 'endif' before the end of the program");
+        assert_eq!(compile_err("input a\nwhile a > 0\n    a = a - 1"), "Error on line 2: 'while' is never ended, expected 'endwhile' before the end of the program");
+        assert_eq!(compile_err("input a\nwhile a > 0\n    if a == 1\n    endif\n    a = a - 1\nendwhile\nif a == 0"), "Error on line 7: 'if' is never ended, expected 'endif' before the end of the program");
+    }
+
+    #[test]
+    fn operator_at_line_start_is_an_error() {
+        let expected = "Error on line 2: Unexpected operator at start of line; did you mean to assign to a variable?";
+        assert_eq!(compile_err("x = 1\n+ x"), expected);
+        assert_eq!(compile_err("x = 1\n== 5"), expected);
+    }
+
+    #[test]
+    fn assigning_to_number_is_an_error() {
+        assert_eq!(compile_err("input x\n5 = x"), "Error on line 2: Cannot assign to a number literal");
+    }
+
+    #[test]
+    fn missing_operator_in_expression_is_an_error() {
+        assert_eq!(compile_err("input a\ninput b\nx = a 5 b"), "Error on line 3 token 3: Expected '+' or '-', found '5'");
+    }
+
+    #[test]
+    fn condition_without_operator_is_an_error() {
+        assert_eq!(compile_err("input a\ninput b\nif a b\nendif"), "Error on line 3 token 2: Expected comparison operator ('==', '!=', '>', '<', '>=' or '<=') between the two operands, found 'b'");
+    }
+
+    #[test]
+    fn error_line_counts_blank_and_comment_lines() {
+        assert_eq!(compile_err("input a\n\n// a comment\n# another\nfoo bar"), "Error on line 5: Identifer at the beginning of a line must be followed by '='");
+    }
+
+    #[test]
+    fn hash_and_slash_comments_are_stripped() {
+        let assembly = compile_ok("# a program\ninput x # read it\nprint x // and print it\n// done");
+        assert_eq!(run(&assembly, &[3]), vec![3]);
+        assert_eq!(assembly, compile_ok("input x\nprint x"));
+    }
+
+    #[test]
+    fn comment_marker_in_string_is_kept() {
+        assert!(compile_ok("asm \"LDA #5\" # load").starts_with("LDA #5\n"));
+        assert!(compile_ok("asm \"LDA //5\" // load").starts_with("LDA //5\n"));
+    }
+
+    #[test]
+    fn dump_constants_lists_lines() {
+        let compiled = compile("input x\nprint x + 7\nprint x - 7\nprint x + 1", &CompileOptions::default(), &mut Vec::new()).unwrap();
+        assert_eq!(compiled.dump_constants(), "const_1 used on line 4\nconst_7 used on lines 2, 3\n");
+    }
+
+    #[test]
+    fn phi_dialect_puts_labels_in_first_column() {
+        let options = CompileOptions { dialect: Dialect::Phi, ..CompileOptions::default() };
+        let assembly = compile_with("input a\nif a > 0\n    print 1\nelse\n    print 2\nendif", &options).unwrap();
+        let lines: Vec<&str> = assembly.lines().collect();
+        assert_eq!(lines[0], "\tINP");
+        assert_eq!(lines[5], "if_2_body\tLDA\tconst_1");
+        assert_eq!(lines[11], "var_a\tDAT\t0");
+        assert!(lines.iter().all(|l| l.split('\t').count() >= 2));
+        assert_eq!(run(&assembly.replace('\t', " "), &[1]), vec![1]);
+    }
+
+    #[test]
+    fn control_flow_graph_of_if_else() {
+        let assembly = compile_ok("input a\nif a > 0\n    print 1\nelse\n    print 2\nendif");
+        let graph = assembly::control_flow_graph(&assembly);
+        assert!(graph.starts_with("digraph program {\n") && graph.ends_with("}\n"));
+        let nodes = graph.lines().filter(|l| l.contains(" [label=") && !l.contains("->")).count();
+        let edges = graph.lines().filter(|l| l.contains("->")).count();
+        assert_eq!((nodes, edges), (4, 4));
+        assert!(graph.contains("\"block_0\" -> \"if_2_else\" [label=\"BRP\"];"));
+        assert!(graph.contains("\"if_2_else\" -> \"if_2_end\";"));
+    }
+
+    #[test]
+    fn program_over_max_cells_is_an_error() {
+        let src = "input a\nif a > 0\n    print 1\nelse\n    print 2\nendif";
+        let options = CompileOptions { max_cells: 14, ..CompileOptions::default() };
+        assert_eq!(compile_with(src, &options).unwrap_err(), "Error: The program needs 15 mailboxes, which is more than the limit of 14");
+        let options = CompileOptions { max_cells: 15, ..CompileOptions::default() };
+        assert!(compile_with(src, &options).is_ok());
+    }
+
+    #[test]
+    fn intern_const_records_each_use() {
+        let mut symbols = Symbols::default();
+        assert_eq!(symbols.intern_const(5, 1), "const_5");
+        assert_eq!(symbols.intern_const(5, 3), "const_5");
+        assert_eq!(symbols.intern_const(-5, 3), "const_neg5");
+        assert_eq!(symbols.consts.len(), 2);
+        assert_eq!(symbols.consts[&5], BTreeSet::from([1, 3]));
+        assert_eq!(symbols.consts[&-5], BTreeSet::from([3]));
+    }
+}
@@ -1,22 +1,356 @@
 use std::env;
 use std::fs;
+use std::io::{self, Write};
+use std::process;
 
+mod assembly;
 mod compiler;
 
-fn main() -> Result<(), ()> {
-    let args: Vec<String> = env::args().collect();
+/// How errors and warnings are printed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MessageFormat {
+    /// Sentences like "Warning on line 3: ..."
+    Human,
+    /// One JSON object per line, for editors
+    Json,
+}
+
+/// Writes a string as a JSON string, with quotes
+fn json_string(s: &str) -> String {
+    let mut json = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => json += "\\\"",
+            '\\' => json += "\\\\",
+            '\n' => json += "\\n",
+            '\t' => json += "\\t",
+            c if c.is_control() => json += &format!("\\u{:04x}", c as u32),
+            c => json.push(c)
+        }
+    }
+    json + "\""
+}
+
+/// Writes a problem as a JSON object.
+/// There is no column information, so the column is always null
+fn json_diagnostic(severity: &str, line: Option<usize>, message: &str) -> String {
+    let line = line.map(|l| l.to_string()).unwrap_or("null".to_string());
+    format!("{{\"line\": {line}, \"column\": null, \"severity\": \"{severity}\", \"message\": {}}}", json_string(message))
+}
+
+/// Formats a warning to be printed
+fn format_warning(warning: &compiler::Warning, format: MessageFormat) -> String {
+    match format {
+        MessageFormat::Human => warning.to_string(),
+        MessageFormat::Json => json_diagnostic("warning", Some(warning.line), &warning.message)
+    }
+}
+
+/// Formats an error to be printed.
+/// Errors start with "Error on line N: " or "Error: ", which is taken apart for JSON
+fn format_error(error: &str, format: MessageFormat) -> String {
+    match format {
+        MessageFormat::Human => error.to_string(),
+        MessageFormat::Json => {
+            let (location, message) = error.split_once(": ").unwrap_or(("", error));
+            let line = location.strip_prefix("Error on line ")
+                .and_then(|rest| rest.split(' ').next())
+                .and_then(|n| n.parse().ok());
+            json_diagnostic("error", line, message)
+        }
+    }
+}
+
+/// Writes the warnings to out, one per line, unless quiet
+fn print_warnings(out: &mut impl Write, warnings: &[compiler::Warning], format: MessageFormat, quiet: bool) -> io::Result<()> {
+    if quiet {
+        return Ok(())
+    }
+    for warning in warnings {
+        writeln!(out, "{}", format_warning(warning, format))?;
+    }
+    Ok(())
+}
+
+/// Checks the program for --check, writing any errors and warnings to out.
+/// Gives whether the program compiles
+fn run_check(program: &str, options: &compiler::CompileOptions, format: MessageFormat, quiet: bool, out: &mut impl Write) -> io::Result<bool> {
+    let mut warnings = Vec::new();
+    let result = compiler::check(program, options, &mut warnings);
+    print_warnings(out, &warnings, format, quiet)?;
+    if let Err(s) = result {
+        writeln!(out, "{}", format_error(&s, format))?;
+        return Ok(false)
+    }
+    Ok(true)
+}
+
+/// What to do, from the command line arguments
+#[derive(Debug, Clone)]
+struct Args {
+    options: compiler::CompileOptions,
+    /// The file to compile
+    path: Option<String>,
+    /// A program to compile instead of a file
+    eval: Option<String>,
+    lint: bool,
+    format: bool,
+    dump_consts: bool,
+    dump_cfg: bool,
+    list_idents: bool,
+    quiet: bool,
+    check: bool,
+    message_format: MessageFormat,
+}
+
+/// Reads the command line arguments, without the name of the program.
+/// Gives a message for an unknown or incomplete option
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Args, String> {
+    let mut parsed = Args {
+        options: compiler::CompileOptions::default(),
+        path: None,
+        eval: None,
+        lint: false,
+        format: false,
+        dump_consts: false,
+        dump_cfg: false,
+        list_idents: false,
+        quiet: false,
+        check: false,
+        message_format: MessageFormat::Human,
+    };
+    let options = &mut parsed.options;
+    let mut comment_markers = Vec::new();
+
+    // Flags can go anywhere, the only other argument is the file to compile
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--strict" => options.strict = true,
+            "--strict-unused" => options.warn_unused = true,
+            "--warnings-as-errors" => options.warnings_as_errors = true,
+            "--quiet" => parsed.quiet = true,
+            "--check" => parsed.check = true,
+            "--lint" => parsed.lint = true,
+            "--format" => parsed.format = true,
+            "--dump-consts" => parsed.dump_consts = true,
+            "--dump-cfg" => parsed.dump_cfg = true,
+            "--list-idents" => parsed.list_idents = true,
+            "--dialect" => {
+                let name = args.next().unwrap_or_default();
+                match assembly::Dialect::from_name(&name) {
+                    Some(dialect) => options.dialect = dialect,
+                    None => return Err(format!("Unknown dialect '{name}', expected 'default' or 'phi'"))
+                }
+            },
+            "--message-format" => {
+                let name = args.next().unwrap_or_default();
+                parsed.message_format = match name.as_str() {
+                    "human" => MessageFormat::Human,
+                    "json" => MessageFormat::Json,
+                    _ => return Err(format!("Unknown message format '{name}', expected 'human' or 'json'"))
+                }
+            },
+            "--eval" => match args.next() {
+                Some(src) => parsed.eval = Some(src),
+                None => return Err("Expected a program after '--eval'".to_string())
+            },
+            "--max-cells" => match args.next().and_then(|n| n.parse().ok()) {
+                Some(n) => options.max_cells = n,
+                None => return Err("Expected a number of mailboxes after '--max-cells'".to_string())
+            },
+            "--separator" => {
+                let name = args.next().unwrap_or_default();
+                let mut chars = name.chars();
+                options.separator = match (name.as_str(), chars.next(), chars.next()) {
+                    ("space", _, _) => Some(' '),
+                    ("newline", _, _) => Some('\n'),
+                    (_, Some(c), None) => Some(c),
+                    _ => return Err(format!("Unknown separator '{name}', expected 'space', 'newline' or a single character"))
+                }
+            },
+            "--define" => match args.next().as_deref().and_then(|d| d.split_once('=')).and_then(|(name, value)| Some((name.to_string(), value.parse().ok()?))) {
+                Some(define) => options.defines.push(define),
+                _ => return Err("Expected NAME=VALUE with a number value after '--define'".to_string())
+            },
+            "--comment" => match args.next() {
+                Some(marker) => comment_markers.push(marker),
+                None => return Err("Expected a comment marker after '--comment'".to_string())
+            },
+            "--target" => {
+                let name = args.next().unwrap_or_default();
+                match assembly::Target::from_name(&name) {
+                    Some(target) => options.target = target,
+                    None => return Err(format!("Unknown target '{name}', expected 'default' or 'short'"))
+                }
+            },
+            flag if flag.starts_with("--") => return Err(format!("Unknown option '{flag}'")),
+            _ => match parsed.path {
+                None => parsed.path = Some(arg),
+                Some(ref path) => return Err(format!("Unexpected argument '{arg}', already compiling '{path}'"))
+            }
+        }
+    }
+
+    // Given comment markers replace the defaults
+    if !comment_markers.is_empty() {
+        options.comment_markers = comment_markers;
+    }
+
+    Ok(parsed)
+}
+
+fn main() {
+    let Args { options, path, eval, lint, format, dump_consts, dump_cfg, list_idents, quiet, check, message_format } = match parse_args(env::args().skip(1)) {
+        Ok(args) => args,
+        Err(message) => {
+            eprintln!("{message}");
+            process::exit(1)
+        }
+    };
+
+    // An inline program is used instead of a file
+    let program = match eval {
+        Some(src) => src,
+        None => fs::read_to_string(path.expect("Should have been given a file to compile"))
+        .expect("Should have been able to read the file")
+    };
+
+    // Only print the program laid out neatly
+    if format {
+        print!("{}", compiler::format_source(&program, &options.comment_markers));
+        return
+    }
 
-    let program = fs::read_to_string(args[1].clone())
-    .expect("Should have been able to read the file");
+    let mut warnings = Vec::new();
 
-    match compiler::compile(&program) {
-        Ok(s) => {
-            print!("{s}"); 
-            return Ok(())
+    // Only print the style problems, and fail if the program doesn't compile
+    if lint {
+        let result = compiler::lint(&program, &options, &mut warnings);
+        print_warnings(&mut io::stdout(), &warnings, message_format, false).expect("Should be able to print the warnings");
+        if let Err(s) = result {
+            println!("{}", format_error(&s, message_format));
+            process::exit(1)
+        }
+        return
+    }
+
+    // Only report problems, all on stderr, and fail if the program doesn't compile
+    if check {
+        if !run_check(&program, &options, message_format, quiet, &mut io::stderr()).expect("Should be able to print the errors") {
+            process::exit(1)
+        }
+        return
+    }
+
+    let result = compiler::compile(&program, &options, &mut warnings);
+
+    // Errors and warnings go to stderr so they don't mix with the assembly
+    print_warnings(&mut io::stderr(), &warnings, message_format, quiet).expect("Should be able to print the warnings");
+
+    match result {
+        Ok(compiled) => {
+            if dump_consts {
+                print!("{}", compiled.dump_constants());
+            }
+            else if list_idents {
+                print!("{}", compiled.list_identifiers());
+            }
+            else if dump_cfg {
+                print!("{}", assembly::control_flow_graph(&compiled.assembly));
+            }
+            else {
+                print!("{}", compiled.assembly);
+            }
         },
         Err(s) => {
-            println!("{s}");
-            return Err(())
+            eprintln!("{}", format_error(&s, message_format));
+            process::exit(1)
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_error_has_line_and_message() {
+        let error = "Error on line 3 token 2: Variable unknown identifier 'b'";
+        assert_eq!(format_error(error, MessageFormat::Json), r#"{"line": 3, "column": null, "severity": "error", "message": "Variable unknown identifier 'b'"}"#);
+    }
+
+    #[test]
+    fn json_error_without_line() {
+        let error = "Error: The program needs 120 mailboxes, which is more than the limit of 100";
+        assert_eq!(format_error(error, MessageFormat::Json), r#"{"line": null, "column": null, "severity": "error", "message": "The program needs 120 mailboxes, which is more than the limit of 100"}"#);
+    }
+
+    #[test]
+    fn json_warning() {
+        let warning = compiler::Warning { line: 2, message: "Condition is always true".to_string() };
+        assert_eq!(format_warning(&warning, MessageFormat::Json), r#"{"line": 2, "column": null, "severity": "warning", "message": "Condition is always true"}"#);
+    }
+
+    #[test]
+    fn json_string_escapes() {
+        assert_eq!(json_string("a \"b\" \\ c\n"), r#""a \"b\" \\ c\n""#);
+    }
+
+    /// Parses the arguments as if they were given on the command line
+    fn parse(args: &[&str]) -> Result<Args, String> {
+        parse_args(args.iter().map(|a| a.to_string()))
+    }
+
+    #[test]
+    fn unknown_option_is_an_error() {
+        assert_eq!(parse(&["--strit", "program.lmc"]).map(|_| ()), Err("Unknown option '--strit'".to_string()));
+    }
+
+    #[test]
+    fn second_file_is_an_error() {
+        assert_eq!(parse(&["a.lmc", "b.lmc"]).map(|_| ()), Err("Unexpected argument 'b.lmc', already compiling 'a.lmc'".to_string()));
+    }
+
+    #[test]
+    fn options_are_read() {
+        let args = parse(&["--strict", "program.lmc", "--comment", ";", "--target", "short"]).expect("Arguments should be valid");
+        assert!(args.options.strict);
+        assert_eq!(args.options.comment_markers, vec![";".to_string()]);
+        assert_eq!(args.options.target, assembly::Target::Short);
+        assert_eq!(args.path.as_deref(), Some("program.lmc"));
+    }
+
+    #[test]
+    fn quiet_prints_no_warnings() {
+        let warnings = vec![compiler::Warning { line: 1, message: "Number 1500 is outside the bounds of LMC numbers".to_string() }];
+        let mut out = Vec::new();
+        print_warnings(&mut out, &warnings, MessageFormat::Human, true).expect("Writing to a Vec can't fail");
+        assert!(out.is_empty());
+
+        print_warnings(&mut out, &warnings, MessageFormat::Human, false).expect("Writing to a Vec can't fail");
+        assert_eq!(String::from_utf8(out).expect("Warnings should be UTF-8"), "Warning on line 1: Number 1500 is outside the bounds of LMC numbers\n");
+    }
+
+    #[test]
+    fn check_passes_valid_program() {
+        let mut out = Vec::new();
+        assert!(run_check("input a\nprint a", &compiler::CompileOptions::default(), MessageFormat::Human, false, &mut out).expect("Writing to a Vec can't fail"));
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn check_fails_broken_program_with_error() {
+        let mut out = Vec::new();
+        assert!(!run_check("print b", &compiler::CompileOptions::default(), MessageFormat::Human, false, &mut out).expect("Writing to a Vec can't fail"));
+        assert_eq!(String::from_utf8(out).expect("Errors should be UTF-8"), "Error on line 1 token 1: Variable unknown identifier 'b'\n");
+    }
+
+    #[test]
+    fn eval_program_compiles() {
+        let args = parse(&["--eval", "input x\noutput x + 1"]).unwrap();
+        assert_eq!(args.path, None);
+        let program = args.eval.expect("Should have an inline program");
+        let compiled = compiler::compile(&program, &args.options, &mut Vec::new()).unwrap();
+        assert_eq!(compiled.assembly, "INP\nSTA var_x\nADD const_1\nOUT\nHLT\n\nvar_x DAT 0\n\nconst_1 DAT 1\n");
+    }
+}